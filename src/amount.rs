@@ -0,0 +1,119 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{ChainError, Result};
+
+// 一个币等于 10^8 个最小单位
+pub const COIN: u64 = 100_000_000;
+
+// 货币总量上限，单个金额以及交易内金额之和都不能超过它
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+// 以最小单位计数的金额，所有加减都需要显式检查溢出
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Amount {
+        Amount(coins * COIN)
+    }
+
+    pub fn as_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    // 金额是否在合法范围内
+    pub fn is_valid(self) -> bool {
+        self <= MAX_MONEY
+    }
+
+    // 求和，溢出或超出合法范围时返回 None
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        let mut total = Amount::ZERO;
+        for amount in amounts {
+            total = total.checked_add(amount).filter(|t| t.is_valid())?;
+        }
+        Some(total)
+    }
+}
+
+// 以币为单位显示，保留 8 位小数
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:08}", self.0 / COIN, self.0 % COIN)
+    }
+}
+
+// 解析以币为单位的十进制金额，例如 "12" 或 "0.5"，不接受负数、超过 8 位的小数和超出范围的金额
+impl FromStr for Amount {
+    type Err = ChainError;
+
+    fn from_str(s: &str) -> Result<Amount> {
+        let invalid = || ChainError::InvalidAmount(s.to_string());
+        let (whole, frac) = match s.split_once('.') {
+            Some((whole, frac)) => (whole, frac),
+            None => (s, ""),
+        };
+        if whole.is_empty() && frac.is_empty() || frac.len() > 8 {
+            return Err(invalid());
+        }
+        if !whole.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let whole: u64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| invalid())? };
+        let frac: u64 = format!("{:0<8}", frac).parse().map_err(|_| invalid())?;
+        let units = whole
+            .checked_mul(COIN)
+            .and_then(|w| w.checked_add(frac))
+            .ok_or_else(invalid)?;
+        let amount = Amount(units);
+        if !amount.is_valid() {
+            return Err(ChainError::InvalidAmount(format!("{} is out of range", s)));
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("12".parse::<Amount>().unwrap(), Amount::from_coins(12));
+        assert_eq!("0.5".parse::<Amount>().unwrap(), Amount::from_units(COIN / 2));
+        assert_eq!(".00000001".parse::<Amount>().unwrap(), Amount::from_units(1));
+        assert_eq!(Amount::from_units(150_000_000).to_string(), "1.50000000");
+
+        for bad in ["", ".", "-1", "1.123456789", "1e3", "21000000.00000001", "99999999999999999999"] {
+            assert!(bad.parse::<Amount>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let one = Amount::from_coins(1);
+        assert_eq!(one.checked_add(one), Some(Amount::from_coins(2)));
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(Amount::from_units(u64::MAX).checked_add(one), None);
+        assert_eq!(Amount::checked_sum([one, one, one]), Some(Amount::from_coins(3)));
+        assert_eq!(Amount::checked_sum([MAX_MONEY, Amount::from_units(1)]), None);
+    }
+}
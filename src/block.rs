@@ -3,31 +3,59 @@ use crypto::{digest::Digest, sha2::Sha256}; // 引入 crypto 库中的 Digest tr
 use log::info;
 use serde::{Deserialize, Serialize}; // 引入 log 库中的 info 宏，用于日志记录
 
-// 定义一个通用结果类型 Result，用于错误处理，T 表示成功的返回值类型，failure::Error 表示错误类型
-use crate::{errors::Result, transaction::Transaction};
+// 定义一个通用结果类型 Result，用于错误处理，T 表示成功的返回值类型，ChainError 表示错误类型
+use crate::{errors::Result, merkle::merkle_root, pow::hash_meets_target, transaction::Transaction};
+
+// 当前的区块版本号
+pub const BLOCK_VERSION: u32 = 1;
+
+// 定义 BlockHeader 结构体，区块头的哈希就是区块的 id，工作量证明只对区块头进行哈希
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32, // 区块版本号
+    pub prev_block_hash: String, // 前一个区块的哈希值，形成链式结构
+    pub merkle_root: Vec<u8>, // 区块内所有交易构成的 Merkle 树根
+    pub witness_root: Vec<u8>, // 区块内所有交易的 wtxid 构成的 Merkle 树根，承诺交易的见证
+    pub timestamp: u128, // 时间戳，记录区块创建的时间
+    pub bits: u32, // 紧凑格式的难度目标，区块头哈希不能大于该目标值
+    pub nonce: u32, // 随机数，用于工作量证明算法
+    pub height: usize, // 区块的高度，表示该区块在链中的位置
+}
 
-// 定义目标哈希的前缀长度为 4，表示我们需要找到哈希值前 4 位是 '0'
-const TARGET_HEXT: usize = 4;
-use merkle_cbt::merkle_tree::{Merge, CBMT};
+impl BlockHeader {
+    // 计算区块头的哈希值
+    pub fn hash(&self) -> Result<String> {
+        let data = bincode::serialize(self)?; // 使用 bincode 将区块头序列化为字节数组
+        let mut hasher = Sha256::new(); // 创建 Sha256 哈希计算器
+        hasher.input(&data[..]); // 输入要进行哈希计算的数据
+        Ok(hasher.result_str())
+    }
+
+    // 验证区块头的哈希是否符合目标，即哈希值不大于 bits 表示的目标值
+    pub fn meets_target(&self) -> Result<bool> {
+        hash_meets_target(&self.hash()?, self.bits)
+    }
+}
 
 // 定义 Block 结构体，表示区块链中的区块
 #[derive(Debug, Clone, Serialize, Deserialize)] // 派生 Debug 和 Clone trait，用于调试和复制
 pub struct Block {
-    timestamp: u128, // 时间戳，记录区块创建的时间
+    header: BlockHeader, // 区块头
+    hash: String, // 当前区块的哈希值，即区块头的哈希
     transactions: Vec<Transaction>, // 交易信息，区块中包含的交易数据
-    prev_block_hash: String, // 前一个区块的哈希值，形成链式结构
-    hash: String, // 当前区块的哈希值
-    height: usize, // 区块的高度，表示该区块在链中的位置
-    nonce: i32 // 随机数，用于工作量证明算法
 }
 
 impl Block {
     pub fn get_transaction(&self) -> &Vec<Transaction> {
         &self.transactions
     }
-    
+
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub(crate) fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.prev_block_hash.clone()
     }
 
     // 获取当前区块的哈希值，返回哈希值的副本
@@ -36,27 +64,30 @@ impl Block {
     }
 
     // 创建并返回创世区块（第一个区块）
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        // 调用 new_block 函数创建创世区块，交易信息为 "Genesis Block"，前一区块哈希为空，区块高度为 0
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+    pub fn new_genesis_block(coinbase: Transaction, bits: u32) -> Result<Block> {
+        // 调用 new_block 函数创建创世区块，前一区块哈希为空，区块高度为 0
+        Block::new_block(vec![coinbase], String::new(), 0, bits)
     }
 
-    // 创建新的区块，接收交易数据、前一区块的哈希值和区块高度作为参数，返回 Result 包含新创建的区块
-    pub fn new_block(data: Vec<Transaction>, prev_block_hash: String, height: usize) -> Result<Block> {
-        // 获取当前时间戳，以毫秒为单位
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)? // 计算自 Unix 纪元以来的时间
-            .as_millis(); // 转换为毫秒表示
-
+    // 创建新的区块，接收交易数据、前一区块的哈希值、区块高度和难度作为参数，返回 Result 包含新创建的区块
+    pub fn new_block(data: Vec<Transaction>, prev_block_hash: String, height: usize, bits: u32) -> Result<Block> {
         // 创建 Block 实例，初始哈希值为空，nonce 为 0
         let mut block = Block {
-            timestamp: timestamp,
-            transactions: data,
-            prev_block_hash: prev_block_hash,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash,
+                merkle_root: Vec::new(),
+                witness_root: Vec::new(),
+                timestamp: now_millis()?,
+                bits,
+                nonce: 0,
+                height,
+            },
             hash: String::new(),
-            height,
-            nonce: 0,
+            transactions: data,
         };
+        block.header.merkle_root = block.hash_transactions();
+        block.header.witness_root = block.hash_witnesses()?;
 
         // 运行工作量证明算法，寻找符合条件的哈希值
         block.run_proof_of_work()?;
@@ -67,78 +98,129 @@ impl Block {
     fn run_proof_of_work(&mut self) -> Result<()> {
         info!("Mining the block"); // 记录日志，表示开始挖矿
         // 不断递增 nonce，直到找到符合目标哈希前缀的哈希值
-        while !self.validate()? {
-            self.nonce += 1; // nonce 自增
+        while !self.header.meets_target()? {
+            match self.header.nonce.checked_add(1) {
+                Some(nonce) => self.header.nonce = nonce, // nonce 自增
+                None => {
+                    // nonce 用尽时增加 coinbase 的额外随机数并更新时间戳，重新开始搜索
+                    self.bump_extra_nonce()?;
+                    self.header.timestamp = now_millis()?;
+                    self.header.nonce = 0;
+                }
+            }
+        }
+        // 获取哈希值并赋值给区块的 hash 字段
+        self.hash = self.header.hash()?;
+        Ok(())
+    }
+
+    // 增加 coinbase 的额外随机数并重新计算 Merkle 根，区块头因此有新的搜索空间。第一笔交易不是 coinbase 时不做修改
+    fn bump_extra_nonce(&mut self) -> Result<()> {
+        if let Some(extra_nonce) = self.transactions.first().and_then(|tx| tx.coinbase_extra_nonce()) {
+            self.transactions[0].set_extra_nonce(extra_nonce.wrapping_add(1))?;
+            self.header.merkle_root = self.hash_transactions();
+            self.header.witness_root = self.hash_witnesses()?;
         }
-        // 准备区块的数据并进行哈希运算
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new(); // 创建一个新的 Sha256 哈希计算器
-        hasher.input(&data[..]); // 输入要进行哈希计算的数据
-        self.hash = hasher.result_str(); // 获取哈希值并赋值给区块的 hash 字段
         Ok(())
     }
-    fn hash_transactions(&mut self) -> Result<Vec<u8>> {
+
+    // 使用交易 id 构建 Merkle 树，返回树根
+    pub fn hash_transactions(&self) -> Vec<u8> {
         let mut transactions = Vec::new();
-        for tx in &mut self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
+        for tx in &self.transactions {
+            transactions.push(tx.id.clone());
         }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
-        Ok(tree.root())
-    }
-
-    // 准备哈希计算的数据，将区块的多个字段序列化为字节数组
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        // 将前一区块哈希、交易数据、时间戳、目标前缀长度和 nonce 组成一个元组
-        let content = (
-            self.prev_block_hash.clone(),
-            self.transactions.clone(),
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce
-        );
-        // 使用 bincode 序列化库将元组序列化为字节数组
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes) // 返回序列化后的字节数组
-    }
-
-    // 验证当前区块的哈希是否符合目标，即哈希的前 TARGET_HEXT 位是否为 '0'
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?; // 准备哈希数据
-        let mut hasher = Sha256::new(); // 创建 Sha256 哈希计算器
-        hasher.input(&data[..]); // 输入要验证的数据
-        let mut vec1: Vec<u8> = vec![]; // 创建一个用于比较的字节数组
-        vec1.resize(TARGET_HEXT, '0' as u8); // 填充数组的前 TARGET_HEXT 个元素为 '0'
-        // 检查生成的哈希值前 TARGET_HEXT 位是否为 '0'
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+        merkle_root(&transactions)
     }
-}
 
-struct MergeTX{}
+    // 使用包含见证的交易哈希构建 Merkle 树，返回树根
+    pub fn hash_witnesses(&self) -> Result<Vec<u8>> {
+        let wtxids = self
+            .transactions
+            .iter()
+            .map(|tx| tx.witness_hash())
+            .collect::<Result<Vec<String>>>()?;
+        Ok(merkle_root(&wtxids))
+    }
 
-impl Merge for MergeTX {
-    type Item = Vec<u8>;
-    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
-        let mut hasher = Sha256::new();
-        let mut data: Vec<u8> = left.clone();
-        data.append(&mut right.clone());
-        hasher.input(&data);
-        let mut re: [u8; 32] = [0; 32];
-        hasher.result(&mut re);
-        re.to_vec()
+    // 重新计算哈希并检查工作量证明，用于校验从外部收到的区块
+    pub fn verify_proof_of_work(&self) -> Result<bool> {
+        Ok(self.header.hash()? == self.hash && self.header.meets_target()?)
     }
 }
 
+// 获取当前时间戳，以毫秒为单位
+fn now_millis() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)? // 计算自 Unix 纪元以来的时间
+        .as_millis()) // 转换为毫秒表示
+}
+
 #[cfg(test)] // 测试模块，用于编写单元测试
 mod tests {
+    use super::*;
     use crate::blockchain::Blockchain;
+    use crate::config::{ChainParams, DataDir, NodeConfig};
+    use crate::script::Script;
+    use crate::validation::{check_block, BlockRejection};
+    use crate::wallet::Wallet;
 
     #[test] // 测试函数
-    fn test_add_block() -> Result<(), failure::Error> {
-        let mut b = Blockchain::new()?; // 使用 `?` 解包结果
-        // b.add_block("data".to_string())?;
-        // b.add_block("data2".to_string())?;
-        // b.add_block("data3".to_string())?;
+    fn test_add_block() -> Result<()> {
+        let address = Wallet::new().get_address();
+        // 使用内存存储，避免污染 data/
+        let b = Blockchain::create_in_memory(address, &NodeConfig::regtest(DataDir::default()))?; // 使用 `?` 解包结果
         dbg!(b);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_header_commits_to_transactions() -> Result<()> {
+        let address = Wallet::new().get_address();
+        let cbtx = Transaction::new_coinbase(address.clone(), String::from("a"), 0)?;
+        let block = Block::new_block(vec![cbtx], String::new(), 0, ChainParams::regtest().genesis_bits)?;
+        assert!(block.verify_proof_of_work()?);
+        assert_eq!(block.get_header().hash()?, block.get_hash());
+        assert_eq!(block.get_header().merkle_root, block.hash_transactions());
+
+        // 替换区块中的交易后，Merkle 根不再匹配
+        let mut tampered = block.clone();
+        tampered.transactions = vec![Transaction::new_coinbase(address, String::from("b"), 0)?];
+        assert_ne!(tampered.get_header().merkle_root, tampered.hash_transactions());
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_commits_to_witnesses() -> Result<()> {
+        let params = ChainParams::regtest();
+        let cbtx = Transaction::new_coinbase(Wallet::new().get_address(), String::from("a"), 0)?;
+        let block = Block::new_block(vec![cbtx], String::new(), 0, params.genesis_bits)?;
+        check_block(&block, &params)?;
+
+        // 修改见证不影响交易 id 和 Merkle 根，但见证 Merkle 根不再匹配
+        let mut tampered = block.clone();
+        tampered.transactions[0].witnesses.push(Script::new());
+        assert_eq!(tampered.get_header().merkle_root, tampered.hash_transactions());
+        let err = check_block(&tampered, &params).unwrap_err();
+        assert_eq!(err.rejection(), Some(&BlockRejection::BadWitnessRoot));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bump_extra_nonce() -> Result<()> {
+        let cbtx = Transaction::new_coinbase(Wallet::new().get_address(), String::from("a"), 3)?;
+        let mut block = Block::new_block(vec![cbtx.clone()], String::new(), 3, ChainParams::regtest().genesis_bits)?;
+        let merkle_root = block.get_header().merkle_root.clone();
+
+        // 额外随机数改变 coinbase 的交易 id，Merkle 根随之重新计算，高度和数据不变
+        block.bump_extra_nonce()?;
+        let bumped = &block.get_transaction()[0];
+        assert_eq!(bumped.coinbase_extra_nonce(), Some(1));
+        assert_eq!(bumped.coinbase_height(), Some(3));
+        assert_ne!(bumped.id, cbtx.id);
+        assert_ne!(block.get_header().merkle_root, merkle_root);
+        assert_eq!(block.get_header().merkle_root, block.hash_transactions());
+        assert_eq!(block.get_header().witness_root, block.hash_witnesses()?);
+        Ok(())
+    }
+}
@@ -1,15 +1,24 @@
 
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::vec;
 
 // 定义 Blockchain 结构体，表示整个区块链
-use crate::block::Block;
-use crate::errors::Result;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::config::NodeConfig;
+use crate::errors::{ChainError, Result};
+use crate::merkle::TxOutProof;
+use crate::pow::{block_work, next_work_required};
+use crate::sigcache::SignatureCache;
+use crate::storage::{ChainStore, Column, MemoryStore, SledStore, WriteBatch};
 use crate::transaction::Transaction;
 use crate::tx::TXOutputs;
-use failure::format_err;
+use crate::utxoset::UTXOSet;
+use crate::validation::{
+    block_subsidy, check_block, check_header_contextual, validate_block, validate_genesis, BlockRejection,
+};
 use log::info;
-const TARGET_HEXT: usize = 4;
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
@@ -17,7 +26,17 @@ const GENESIS_COINBASE_DATA: &str =
 #[derive(Debug)] // 派生 Debug trait，用于调试
 pub struct Blockchain {
     current_hash: String,
-    db: sled::Db,
+    store: Arc<dyn ChainStore>,
+    config: NodeConfig,
+    sig_cache: SignatureCache, // 交易进入交易池时验证过的签名，连接区块时不再重复验证。命令行每次调用都新建 Blockchain，缓存不会跨调用保留
+}
+
+// 处理一个区块之后主链的变化：断开的区块按断开的先后排列，连接的区块按连接的先后排列。
+// 持有交易池的调用者据此移除已确认的交易，并把断开区块中的交易重新放回交易池
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
 }
 
 pub struct BlockchainIterator<'a> {
@@ -26,87 +45,258 @@ pub struct BlockchainIterator<'a> {
 }
 
 impl Blockchain {
-    pub fn new() -> Result<Blockchain> {
+    pub fn new(config: &NodeConfig) -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
+        let store = SledStore::open(config.data_dir.chainstate())?;
+        Blockchain::open(Arc::new(store), config)
+    }
+
+    pub fn open(store: Arc<dyn ChainStore>, config: &NodeConfig) -> Result<Blockchain> {
+        let last_hash = store
+            .get_tip()?
+            .ok_or_else(|| ChainError::Storage(String::from("Must create a new block database first")))?;
         info!("Found block database");
-        let last_hash = String::from_utf8(hash.to_vec())?;
         Ok(Blockchain {
-            current_hash: last_hash.clone(),
-            db,
+            current_hash: last_hash,
+            store,
+            config: config.clone(),
+            sig_cache: SignatureCache::default(),
         })
     }
 
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    pub fn create_blockchain(address: String, config: &NodeConfig) -> Result<Blockchain> {
         info!("Creating new blockchain");
 
-        if let Err(_) = std::fs::remove_dir_all("data/blocks") {
-            info!("blocks not exist to delete")
+        // 只清空新链会重建的分区，已保存的交易池和撤销记录保留
+        let store = SledStore::open(config.data_dir.chainstate())?;
+        for column in Column::CHAIN {
+            store.clear(column)?;
         }
-
-        let db = sled::open("data/blocks")?;
         info!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
+        Blockchain::create_with_store(address, Arc::new(store), config)
+    }
+
+    // 在内存中创建区块链，不访问磁盘，适合测试和模拟
+    pub fn create_in_memory(address: String, config: &NodeConfig) -> Result<Blockchain> {
+        Blockchain::create_with_store(address, Arc::new(MemoryStore::new()), config)
+    }
+
+    pub fn create_with_store(address: String, store: Arc<dyn ChainStore>, config: &NodeConfig) -> Result<Blockchain> {
+        let subsidy = block_subsidy(0, &config.params);
+        let cbtx = Transaction::new_coinbase_with_value(address, String::from(GENESIS_COINBASE_DATA), 0, 0, subsidy)?;
+        let genesis: Block = Block::new_genesis_block(cbtx, config.params.genesis_bits)?;
+        validate_genesis(&genesis, &config.params)?;
+        let mut batch = WriteBatch::new();
+        batch.put_block(&genesis)?;
+        batch.put_chain_work(&genesis.get_hash(), block_work(genesis.get_header().bits));
+        UTXOSet::connect_block(store.as_ref(), &genesis, &mut batch)?;
+        batch.put_height(0, &genesis.get_hash());
+        batch.set_tip(&genesis.get_hash());
+        store.write(batch)?;
+        Ok(Blockchain {
             current_hash: genesis.get_hash(),
-            db,
-        };
-        bc.db.flush()?;
-        Ok(bc)
+            store,
+            config: config.clone(),
+            sig_cache: SignatureCache::default(),
+        })
     }
 
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
+
+    pub fn store(&self) -> &dyn ChainStore {
+        self.store.as_ref()
+    }
+
+    pub fn sig_cache(&self) -> &SignatureCache {
+        &self.sig_cache
+    }
+
+    pub fn tip(&self) -> &str {
+        &self.current_hash
+    }
+
+    // 当前链尖的高度
+    pub fn get_best_height(&self) -> Result<usize> {
+        Ok(self.get_block(&self.current_hash)?.get_header().height)
+    }
+
+    // 当前链尖的区块头，新交易将进入它之后的区块
+    pub fn get_tip_header(&self) -> Result<BlockHeader> {
+        Ok(self.get_block(&self.current_hash)?.get_header().clone())
+    }
+
+    pub fn get_block_hash(&self, height: usize) -> Result<String> {
+        self.store
+            .get_block_hash(height)?
+            .ok_or_else(|| ChainError::NotFound(format!("block at height {}", height)))
+    }
+
+    pub fn get_block_by_height(&self, height: usize) -> Result<Block> {
+        self.get_block(&self.get_block_hash(height)?)
+    }
+
+    // 从创世区块到该区块的累计工作量，用于在分叉之间选择主链
+    pub fn get_chain_work(&self, hash: &str) -> Result<u128> {
+        self.store
+            .get_chain_work(hash)?
+            .ok_or_else(|| ChainError::Storage(format!("chain work for block {} is missing", hash)))
+    }
+
+    // 在当前链尖之后挖出新区块。新区块直接扩展主链，不会断开任何区块，连接的就是返回的区块
     pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        let last_hash = self.db.get("LAST")?.unwrap();
+        let parent = self.get_block(&self.current_hash)?;
+        let bits = next_work_required(self.store(), parent.get_header(), &self.config.params)?;
 
-        let new_block = Block::new_block(transactions, String::from_utf8(last_hash.to_vec())?, TARGET_HEXT)?;
-        self.db.insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
-        self.current_hash = new_block.get_hash();
+        let new_block = Block::new_block(transactions, parent.get_hash(), parent.get_header().height + 1, bits)?;
+        self.process_block(&new_block)?;
         Ok(new_block)
     }
 
-    fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
-        let mut spent_txos: HashMap<String, Vec<u32>> = HashMap::new();
-        let mut unspent_txs: Vec<Transaction> = Vec::new();
+    // 接收一个区块，可能扩展主链，也可能属于侧链；侧链的累计工作量超过主链时切换过去
+    pub fn process_block(&mut self, block: &Block) -> Result<ChainUpdate> {
+        let hash = block.get_hash();
+        if self.store.get_chain_work(&hash)?.is_some() {
+            return Ok(ChainUpdate::default());
+        }
 
-        for block in self.iter() {
-            for tx in block.get_transaction() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spent_txos.get(&tx.id) {
-                        if ids.contains(&(index as u32)) {
-                            continue;
-                        }
-                    }
+        check_block(block, &self.config.params)?;
+        let parent = self
+            .store
+            .get_block(&block.get_prev_hash())?
+            .ok_or_else(|| BlockRejection::UnknownParent(block.get_prev_hash()))?;
+        check_header_contextual(block.get_header(), parent.get_header(), self.store(), &self.config.params)?;
+
+        let work = self
+            .get_chain_work(&parent.get_hash())?
+            .saturating_add(block_work(block.get_header().bits));
+        let mut children = self.store.get_children(&parent.get_hash())?;
+        children.push(hash.clone());
+        let mut batch = WriteBatch::new();
+        batch.put_block(block)?;
+        batch.put_chain_work(&hash, work);
+        batch.put_children(&parent.get_hash(), &children)?;
+        self.store.write(batch)?;
+
+        if work > self.get_chain_work(&self.current_hash)? {
+            return self.reorganize(block);
+        }
+        Ok(ChainUpdate::default())
+    }
 
-                    if tx.vout[index].can_be_unlock_with(address) {
-                        unspent_txs.push(tx.to_owned())
-                    }
-                }
+    // 切换到以 new_tip 结尾的分支：先断开到分叉点为止的区块，再依次连接新分支上的区块
+    fn reorganize(&mut self, new_tip: &Block) -> Result<ChainUpdate> {
+        let mut branch = vec![new_tip.clone()];
+        let mut fork = self.get_block(&new_tip.get_prev_hash())?;
+        while !self.is_on_main_chain(&fork)? {
+            let prev = self.get_block(&fork.get_prev_hash())?;
+            branch.push(fork);
+            fork = prev;
+        }
 
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        if i.can_unlock_output_with(address) {
-                            match spent_txos.get_mut(&i.txid) {
-                                Some(v) => {
-                                    v.push(i.vout.try_into().unwrap());
-                                }
-                                None => {
-                                    spent_txos.insert(i.txid.clone(), vec![i.vout.try_into().unwrap()]);
-                                }
-                            }
-                        }
+        let old_work = self.get_chain_work(&self.current_hash)?;
+        let mut disconnected = Vec::new();
+        while self.current_hash != fork.get_hash() {
+            disconnected.push(self.disconnect_tip()?);
+        }
+
+        let mut connected = Vec::new();
+        for block in branch.into_iter().rev() {
+            if let Err(err) = self.connect_block(&block) {
+                // 无效区块及其后代不再保留，避免之后重复尝试
+                self.prune_invalid(&block)?;
+
+                // 已连接的部分不如原来的主链时，恢复原来的主链
+                if self.get_chain_work(&self.current_hash)? <= old_work {
+                    while self.current_hash != fork.get_hash() {
+                        self.disconnect_tip()?;
+                    }
+                    for block in disconnected.iter().rev() {
+                        self.connect_block(block)?;
                     }
+                    return Err(err);
                 }
+                // 已连接的部分工作量更多，链尖已经切换到新分支上
+                info!("block {} is invalid, stopping at {}: {}", block.get_hash(), self.current_hash, err);
+                break;
+            }
+            connected.push(block);
+        }
+        if !disconnected.is_empty() {
+            info!("reorganized {} blocks, new tip {}", disconnected.len(), self.current_hash);
+        }
+        Ok(ChainUpdate { disconnected, connected })
+    }
+
+    // 删除无效区块及其所有已保存的后代和它们的累计工作量，之后接在这些区块上的区块会因为父区块未知而被拒绝
+    fn prune_invalid(&self, invalid: &Block) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        let mut siblings = self.store.get_children(&invalid.get_prev_hash())?;
+        siblings.retain(|hash| *hash != invalid.get_hash());
+        batch.put_children(&invalid.get_prev_hash(), &siblings)?;
+        let mut pending = vec![invalid.get_hash()];
+        while let Some(hash) = pending.pop() {
+            pending.extend(self.store.get_children(&hash)?);
+            batch.delete_block(&hash);
+        }
+        self.store.write(batch)
+    }
+
+    // 将区块接到链尖之后，区块、UTXO 集、高度索引和链尖在同一批次中写入
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        validate_block(block, self)?;
+        let mut batch = WriteBatch::new();
+        UTXOSet::connect_block(self.store(), block, &mut batch)?;
+        batch.put_height(block.get_header().height, &block.get_hash());
+        batch.set_tip(&block.get_hash());
+        self.store.write(batch)?;
+        self.current_hash = block.get_hash();
+        Ok(())
+    }
+
+    // 断开链尖区块，区块本身仍然保留在侧链中
+    fn disconnect_tip(&mut self) -> Result<Block> {
+        let block = self.get_block(&self.current_hash)?;
+        let mut batch = WriteBatch::new();
+        UTXOSet::disconnect_block(self.store(), &block, &mut batch)?;
+        batch.delete_height(block.get_header().height);
+        batch.set_tip(&block.get_prev_hash());
+        self.store.write(batch)?;
+        self.current_hash = block.get_prev_hash();
+        Ok(block)
+    }
+
+    fn is_on_main_chain(&self, block: &Block) -> Result<bool> {
+        Ok(self.store.get_block_hash(block.get_header().height)? == Some(block.get_hash()))
+    }
+
+    fn get_block(&self, hash: &str) -> Result<Block> {
+        self.store
+            .get_block(hash)?
+            .ok_or_else(|| ChainError::Storage(format!("block {} is missing", hash)))
+    }
+
+    // 主链上已发行的货币总量：每个区块新创建的输出减去撤销记录中它花费掉的输出，差值就是该区块新发行的数量
+    pub fn get_total_supply(&self) -> Result<Amount> {
+        let mut supply = Amount::ZERO;
+        for block in self.iter() {
+            let undo = self
+                .store
+                .get_undo(&block.get_hash())?
+                .ok_or_else(|| ChainError::Storage(format!("undo data for block {} is missing", block.get_hash())))?;
+            let spent = Amount::checked_sum(undo.spent.iter().map(|s| s.output.value));
+            let created = Amount::checked_sum(
+                block.get_transaction().iter().flat_map(|tx| tx.vout.iter()).map(|out| out.value),
+            );
+            supply = match (created, spent) {
+                (Some(created), Some(spent)) => supply.checked_add(created).and_then(|s| s.checked_sub(spent)),
+                _ => None,
             }
+            .ok_or_else(|| ChainError::InvalidAmount(format!("total supply at block {} is out of range", block.get_hash())))?;
         }
-        unspent_txs
+        Ok(supply)
     }
 
     pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
@@ -117,25 +307,19 @@ impl Blockchain {
         for block in self.iter() {
             for tx in block.get_transaction() {
                 for index in 0..tx.vout.len() {
+                    if tx.vout[index].is_unspendable() {
+                        continue;
+                    }
                     if let Some(ids) = spend_txos.get(&tx.id) {
                         if ids.contains(&(index as i32)) {
                             continue;
                         }
                     }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                    tx.id.clone(),
-                                    TXOutputs {
-                                        outputs: vec![tx.vout[index].clone()],
-                                    },
-                                );
-                        }
-                    } 
+                    let entry = utxos.entry(tx.id.clone()).or_default();
+                    entry.height = block.get_header().height;
+                    entry.is_coinbase = tx.is_coinbase();
+                    entry.outputs.insert(index as i32, tx.vout[index].clone());
                 }
 
                 if !tx.is_coinbase() {
@@ -165,7 +349,16 @@ impl Blockchain {
                 }
             }
         }
-        Err(format_err!("Transaction is not found"))
+        Err(ChainError::NotFound(format!("transaction {}", id)))
+    }
+
+    pub fn get_txout_proof(&self, txid: &str) -> Result<TxOutProof> {
+        for b in self.iter() {
+            if b.get_transaction().iter().any(|tx| tx.id == txid) {
+                return TxOutProof::new(&b, &[txid.to_string()]);
+            }
+        }
+        Err(ChainError::NotFound(format!("transaction {}", txid)))
     }
 
     pub fn get_prev_txs(&self, tx:&Transaction) -> Result<HashMap<String, Transaction>> {
@@ -184,10 +377,10 @@ impl Blockchain {
     }
 
 
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.current_hash.clone(),
-            bc: &self,
+            bc: self,
         }
     }
 
@@ -199,18 +392,9 @@ impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encoded_block) = self.bc.db.get(&self.current_hash) {
-            return match encoded_block {
-                Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            };
+        if let Ok(Some(block)) = self.bc.store.get_block(&self.current_hash) {
+            self.current_hash = block.get_prev_hash();
+            return Some(block);
         }
         None
     }
@@ -219,16 +403,285 @@ impl<'a> Iterator for BlockchainIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{spendable_config, temp_config, DataDir};
+    use crate::script::Script;
+    use crate::tx::{TXInput, TXOutput, SEQUENCE_FINAL};
+    use crate::wallet::Wallet;
 
     #[test]
     fn test_add_block() {
-        let mut b = Blockchain::new().unwrap();
-        // b.add_block("data 1".to_string());
-        // b.add_block("data 2".to_string());
-        // b.add_block("data 3".to_string());
+        let config = NodeConfig::regtest(DataDir::default());
+        let address = Wallet::new().get_address();
+        let mut b = Blockchain::create_in_memory(address.clone(), &config).unwrap();
+        let cbtx = Transaction::new_coinbase(address, String::from("block 1"), 1).unwrap();
+        b.add_block(vec![cbtx]).unwrap();
+        assert_eq!(b.iter().count(), 2);
 
         for item in b.iter() {
             println!("item {:?}", item)
         }
     }
+
+    #[test]
+    fn test_difficulty_retarget() {
+        let mut config = NodeConfig::regtest(DataDir::default());
+        config.params.pow_no_retargeting = false;
+        config.params.retarget_interval = 2;
+        let address = Wallet::new().get_address();
+        let mut b = Blockchain::create_in_memory(address.clone(), &config).unwrap();
+
+        let cbtx = Transaction::new_coinbase(address.clone(), String::from("block 1"), 1).unwrap();
+        let block1 = b.add_block(vec![cbtx]).unwrap();
+        assert_eq!(block1.get_header().height, 1);
+        assert_eq!(block1.get_header().bits, config.params.genesis_bits);
+
+        // 区块出得远比预期快，难度按上限提高到 4 倍
+        let cbtx = Transaction::new_coinbase(address, String::from("block 2"), 2).unwrap();
+        let block2 = b.add_block(vec![cbtx]).unwrap();
+        assert_eq!(block2.get_header().height, 2);
+        assert_eq!(block2.get_header().bits, 0x201fffff);
+    }
+
+    #[test]
+    fn test_height_index() {
+        let config = NodeConfig::regtest(DataDir::default());
+        let address = Wallet::new().get_address();
+        let mut b = Blockchain::create_in_memory(address.clone(), &config).unwrap();
+        let genesis = b.iter().next().unwrap();
+        let mut hashes = vec![genesis.get_hash()];
+        for i in 1..4 {
+            let cbtx = Transaction::new_coinbase(address.clone(), format!("block {}", i), i).unwrap();
+            hashes.push(b.add_block(vec![cbtx]).unwrap().get_hash());
+        }
+
+        assert_eq!(b.get_best_height().unwrap(), 3);
+        for (height, hash) in hashes.iter().enumerate() {
+            assert_eq!(&b.get_block_hash(height).unwrap(), hash);
+            assert_eq!(b.get_block_by_height(height).unwrap().get_header().height, height);
+        }
+        assert!(b.get_block_hash(4).is_err());
+    }
+
+    // 在指定的父区块上挖出区块，模拟其他矿工
+    fn mine_on(parent: &Block, data: &str, address: &str) -> Block {
+        let cbtx = Transaction::new_coinbase(address.to_string(), data.to_string(), parent.get_header().height + 1).unwrap();
+        let header = parent.get_header();
+        Block::new_block(vec![cbtx], parent.get_hash(), header.height + 1, header.bits).unwrap()
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let config = spendable_config();
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut b = Blockchain::create_in_memory(alice.get_address(), &config).unwrap();
+        let genesis = b.iter().next().unwrap();
+        let genesis_cb = genesis.get_transaction()[0].id.clone();
+
+        // 主链上 alice 把创世区块的奖励转给 bob
+        let mut pay = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: genesis_cb.clone(),
+                vout: 0,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput::new(Amount::from_coins(40), bob.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        pay.id = pay.hash().unwrap();
+        b.sign_transaction(&mut pay, &alice.secret_key).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("a1"), 1).unwrap();
+        let a1 = b.add_block(vec![cbtx, pay.clone()]).unwrap();
+        assert!(b.store().get_utxos(&genesis_cb).unwrap().is_none());
+
+        // 工作量相同的侧链区块只保存，不切换
+        let b1 = mine_on(&genesis, "b1", &bob.get_address());
+        b.process_block(&b1).unwrap();
+        assert_eq!(b.tip(), a1.get_hash());
+        assert!(b.store().get_block(&b1.get_hash()).unwrap().is_some());
+
+        // 侧链更长后切换，转账被撤销，创世区块的输出恢复
+        let b2 = mine_on(&b1, "b2", &bob.get_address());
+        b.process_block(&b2).unwrap();
+        assert_eq!(b.tip(), b2.get_hash());
+        assert_eq!(b.get_block_hash(1).unwrap(), b1.get_hash());
+        assert!(b.store().get_utxos(&pay.id).unwrap().is_none());
+        assert!(b.store().get_utxos(&a1.get_transaction()[0].id).unwrap().is_none());
+        assert!(b.store().get_utxos(&genesis_cb).unwrap().unwrap().outputs.contains_key(&0));
+        assert!(b.get_chain_work(&b2.get_hash()).unwrap() > b.get_chain_work(&a1.get_hash()).unwrap());
+
+        // 原来的分支重新领先后再切换回去
+        let a2 = mine_on(&a1, "a2", &alice.get_address());
+        let a3 = mine_on(&a2, "a3", &alice.get_address());
+        b.process_block(&a2).unwrap();
+        b.process_block(&a3).unwrap();
+        assert_eq!(b.tip(), a3.get_hash());
+        assert_eq!(b.get_best_height().unwrap(), 3);
+        assert!(b.store().get_utxos(&pay.id).unwrap().is_some());
+        assert!(b.store().get_utxos(&genesis_cb).unwrap().is_none());
+        assert!(b.store().get_utxos(&b2.get_transaction()[0].id).unwrap().is_none());
+
+        // 增量维护的 UTXO 集与重新扫描主链的结果一致
+        let mut stored: Vec<String> = b.store().utxos().unwrap().into_iter().map(|(txid, _)| txid).collect();
+        let mut scanned: Vec<String> = b.find_utxo().into_keys().collect();
+        stored.sort();
+        scanned.sort();
+        assert_eq!(stored, scanned);
+    }
+
+    #[test]
+    fn test_reject_invalid_branch() {
+        let config = NodeConfig::regtest(DataDir::default());
+        let address = Wallet::new().get_address();
+        let mut b = Blockchain::create_in_memory(address.clone(), &config).unwrap();
+        let genesis = b.iter().next().unwrap();
+        let a1 = mine_on(&genesis, "a1", &address);
+        b.process_block(&a1).unwrap();
+
+        // 侧链的第二个区块花费了不存在的输出，切换失败后保留原来的主链
+        let b1 = mine_on(&genesis, "b1", &address);
+        let mut bad = Transaction::new_coinbase(address.clone(), String::from("bad"), 2).unwrap();
+        bad.vin[0].txid = String::from("missing");
+        bad.vin[0].script_sig = Script::new();
+        bad.id = bad.hash().unwrap();
+        let cbtx = Transaction::new_coinbase(address.clone(), String::from("b2"), 2).unwrap();
+        let b2 = Block::new_block(vec![cbtx, bad], b1.get_hash(), 2, b1.get_header().bits).unwrap();
+        b.process_block(&b1).unwrap();
+        let err = b.process_block(&b2).unwrap_err();
+        assert!(matches!(err.rejection(), Some(BlockRejection::MissingInput { .. })));
+        assert_eq!(b.tip(), a1.get_hash());
+        assert_eq!(b.get_block_hash(1).unwrap(), a1.get_hash());
+        assert!(b.store().get_block(&b2.get_hash()).unwrap().is_none());
+
+        // 父区块未知的区块被拒绝
+        let orphan = mine_on(&b2, "orphan", &address);
+        let err = b.process_block(&orphan).unwrap_err();
+        assert!(matches!(err.rejection(), Some(BlockRejection::UnknownParent(_))));
+    }
+
+    #[test]
+    fn test_prune_invalid_descendants() {
+        let config = NodeConfig::regtest(DataDir::default());
+        let address = Wallet::new().get_address();
+        let mut b = Blockchain::create_in_memory(address.clone(), &config).unwrap();
+        let genesis = b.iter().next().unwrap();
+        let a1 = mine_on(&genesis, "a1", &address);
+        let a2 = mine_on(&a1, "a2", &address);
+        let a3 = mine_on(&a2, "a3", &address);
+        for block in [&a1, &a2, &a3] {
+            b.process_block(block).unwrap();
+        }
+
+        // 侧链的 b2 无效，但工作量不足时只保存不连接，后代 b3 同样只保存
+        let b1 = mine_on(&genesis, "b1", &address);
+        let mut bad = Transaction::new_coinbase(address.clone(), String::from("bad"), 2).unwrap();
+        bad.vin[0].txid = String::from("missing");
+        bad.vin[0].script_sig = Script::new();
+        bad.id = bad.hash().unwrap();
+        let cbtx = Transaction::new_coinbase(address.clone(), String::from("b2"), 2).unwrap();
+        let b2 = Block::new_block(vec![cbtx, bad], b1.get_hash(), 2, b1.get_header().bits).unwrap();
+        let b3 = mine_on(&b2, "b3", &address);
+        for block in [&b1, &b2, &b3] {
+            b.process_block(block).unwrap();
+        }
+        assert_eq!(b.tip(), a3.get_hash());
+
+        // b4 使侧链领先，切换时 b2 连接失败，b2 及其所有后代连同累计工作量一起删除
+        let b4 = mine_on(&b3, "b4", &address);
+        let err = b.process_block(&b4).unwrap_err();
+        assert!(matches!(err.rejection(), Some(BlockRejection::MissingInput { .. })));
+        assert_eq!(b.tip(), a3.get_hash());
+        assert!(b.store().get_block(&b1.get_hash()).unwrap().is_some());
+        assert!(b.store().get_children(&b1.get_hash()).unwrap().is_empty());
+        for block in [&b2, &b3, &b4] {
+            assert!(b.store().get_block(&block.get_hash()).unwrap().is_none());
+            assert!(b.store().get_chain_work(&block.get_hash()).unwrap().is_none());
+            assert!(b.store().get_children(&block.get_hash()).unwrap().is_empty());
+        }
+        assert_eq!(b.store().get_children(&genesis.get_hash()).unwrap(), vec![a1.get_hash(), b1.get_hash()]);
+
+        // 接在无效区块后代上的区块被拒绝，而不是在查找祖先时遇到存储错误
+        let b5 = mine_on(&b4, "b5", &address);
+        let err = b.process_block(&b5).unwrap_err();
+        assert_eq!(err.rejection(), Some(&BlockRejection::UnknownParent(b4.get_hash())));
+    }
+
+    #[test]
+    fn test_subsidy_halving() {
+        let mut config = spendable_config();
+        config.params.subsidy_halving_interval = 2;
+        let alice = Wallet::new();
+        let mut b = Blockchain::create_in_memory(alice.get_address(), &config).unwrap();
+        let genesis_cb = b.iter().next().unwrap().get_transaction()[0].clone();
+
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+        b.add_block(vec![cbtx]).unwrap();
+
+        // 高度 2 的奖励已经减半
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 2"), 2).unwrap();
+        let err = b.add_block(vec![cbtx]).unwrap_err();
+        assert_eq!(
+            err.rejection(),
+            Some(&BlockRejection::BadCoinbaseValue { value: Amount::from_coins(100), max: Amount::from_coins(50) })
+        );
+
+        // 手续费归矿工但不是新发行的货币
+        let mut pay = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: genesis_cb.id.clone(),
+                vout: 0,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput::new(Amount::from_coins(90), alice.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        pay.id = pay.hash().unwrap();
+        b.sign_transaction(&mut pay, &alice.secret_key).unwrap();
+        let cbtx = Transaction::new_coinbase_with_value(alice.get_address(), String::from("block 2"), 2, 0, Amount::from_coins(60)).unwrap();
+        b.add_block(vec![cbtx, pay]).unwrap();
+        let cbtx = Transaction::new_coinbase_with_value(alice.get_address(), String::from("block 3"), 3, 0, Amount::from_coins(50)).unwrap();
+        b.add_block(vec![cbtx]).unwrap();
+
+        assert_eq!(b.get_total_supply().unwrap(), Amount::from_coins(100 + 100 + 50 + 50));
+    }
+
+    #[test]
+    fn test_reopen_sled_chain() {
+        let config = temp_config("bc_reopen");
+        let address = Wallet::new().get_address();
+        let created = Blockchain::create_blockchain(address, &config).unwrap();
+        let genesis = created.iter().next().unwrap();
+        drop(created);
+
+        let b = Blockchain::new(&config).unwrap();
+        assert_eq!(b.iter().next().unwrap().get_hash(), genesis.get_hash());
+        drop(b);
+        std::fs::remove_dir_all(config.data_dir.root()).unwrap();
+    }
+
+    #[test]
+    fn test_recreate_keeps_mempool() {
+        let config = temp_config("bc_recreate");
+        let created = Blockchain::create_blockchain(Wallet::new().get_address(), &config).unwrap();
+        let old_tip = created.tip().to_string();
+        let mut batch = WriteBatch::new();
+        batch.put(Column::Mempool, b"tx", vec![1]);
+        created.store().write(batch).unwrap();
+        drop(created);
+
+        // 重新创建只替换链数据，交易池分区不受影响
+        let b = Blockchain::create_blockchain(Wallet::new().get_address(), &config).unwrap();
+        assert_ne!(b.tip(), old_tip);
+        assert!(b.store().get_block(&old_tip).unwrap().is_none());
+        assert_eq!(b.iter().count(), 1);
+        assert_eq!(b.store().get(Column::Mempool, b"tx").unwrap(), Some(vec![1]));
+        drop(b);
+        std::fs::remove_dir_all(config.data_dir.root()).unwrap();
+    }
 }
@@ -1,13 +1,19 @@
+use std::fmt;
 use std::process::exit;
+use std::str::FromStr;
 
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, ArgMatches, Command};
 
+use crate::amount::Amount;
 use crate::blockchain::Blockchain;
-use crate::errors::Result;
+use crate::config::{DataDir, NodeConfig, DEFAULT_DATA_DIR};
+use crate::errors::{ChainError, Result};
+use crate::mempool::Mempool;
+use crate::merkle::TxOutProof;
+use crate::miner::mine_block;
 use crate::transaction::Transaction;
 use crate::utxoset::UTXOSet;
-use crate::wallet::Wallets;
+use crate::wallet::{htlc_script, script_for_address, Wallets};
 
 pub struct Cli {
 }
@@ -22,6 +28,11 @@ impl Cli {
             .version("0.1")
             .author("lllu238744@gmail.com")
             .about("blockchain in rust: a simple blockchain for learning")
+            .arg(arg!(--datadir <DIR> "'Root directory for blocks, utxos and wallets'")
+                .global(true)
+                .default_value(DEFAULT_DATA_DIR))
+            .arg(arg!(--regtest "'Use the regression test chain with trivial difficulty'")
+                .global(true))
 
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
 
@@ -42,37 +53,100 @@ impl Cli {
                     .about("send in the blockchain")
                     .arg(arg!(<FROM>"'Source wallet address'"))
                     .arg(arg!(<TO>"'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>"'Amount to transfer'")))
+                    .arg(arg!(<AMOUNT>"'Amount to transfer, in coins with up to 8 decimals'"))
+                    .arg(arg!(--mine "'Mine a block with the mempool right away, rewarding the sender'"))
+                    .arg(arg!(--fee <FEE> "'Absolute fee to pay'").conflicts_with("feerate"))
+                    .arg(arg!(--feerate <RATE> "'Fee in coins to pay per 1000 bytes'")))
+            .subcommand(
+                Command::new("createhtlc")
+                    .about("lock coins in a hash time-locked contract for an atomic swap")
+                    .arg(arg!(<FROM>"'Sender wallet address, which can refund after the timeout'"))
+                    .arg(arg!(<TO>"'Recipient address, which can claim with the preimage'"))
+                    .arg(arg!(<AMOUNT>"'Amount to lock, in coins with up to 8 decimals'"))
+                    .arg(arg!(<HASH>"'Hex sha256 hash of the secret preimage'"))
+                    .arg(arg!(<TIMEOUT>"'Block height (or unix time in ms) after which the sender can refund'"))
+                    .arg(arg!(--mine "'Mine a block with the mempool right away, rewarding the sender'"))
+                    .arg(arg!(--fee <FEE> "'Absolute fee to pay'")))
+            .subcommand(
+                Command::new("claimhtlc")
+                    .about("claim a hash time-locked contract by revealing the preimage")
+                    .arg(arg!(<TXID>"'Transaction that created the contract'"))
+                    .arg(arg!(<VOUT>"'Index of the contract output'"))
+                    .arg(arg!(<TO>"'Recipient wallet address named in the contract'"))
+                    .arg(arg!(<PREIMAGE>"'Hex secret whose sha256 hash locks the contract'"))
+                    .arg(arg!(--mine "'Mine a block with the mempool right away, rewarding the recipient'"))
+                    .arg(arg!(--fee <FEE> "'Absolute fee to pay'")))
+            .subcommand(
+                Command::new("refundhtlc")
+                    .about("take back the coins of an expired hash time-locked contract")
+                    .arg(arg!(<TXID>"'Transaction that created the contract'"))
+                    .arg(arg!(<VOUT>"'Index of the contract output'"))
+                    .arg(arg!(<TO>"'Sender wallet address named in the contract'"))
+                    .arg(arg!(--mine "'Mine a block with the mempool right away, rewarding the sender'"))
+                    .arg(arg!(--fee <FEE> "'Absolute fee to pay'")))
+            .subcommand(
+                Command::new("senddata")
+                    .about("anchor data such as a document hash in an unspendable output")
+                    .arg(arg!(<FROM>"'Wallet address that pays the fee'"))
+                    .arg(arg!(<HEX>"'Hex data to anchor'"))
+                    .arg(arg!(--mine "'Mine a block with the mempool right away, rewarding the sender'"))
+                    .arg(arg!(--fee <FEE> "'Absolute fee to pay'")))
+            .subcommand(Command::new("getdata")
+                .about("print the data anchored by a transaction")
+                .arg(arg!(<TXID>"'The transaction id'")))
+            .subcommand(Command::new("mine")
+                .about("mine a block containing the transactions in the mempool")
+                .arg(arg!(<ADDRESS>"'The address to send the block reward to'"))
+                .arg(arg!([COUNT]"'Number of blocks to mine'").default_value("1")))
+            .subcommand(Command::new("getrawmempool").about("list the ids of transactions in the mempool"))
             .subcommand(Command::new("reindex").about("reindex UTXO"))
+            .subcommand(Command::new("gettotalsupply").about("print the amount of coins issued so far"))
+            .subcommand(Command::new("getblockcount").about("print the height of the chain tip"))
+            .subcommand(Command::new("getblockhash")
+                .about("print the hash of the block at the given height")
+                .arg(arg!(<HEIGHT>"'The block height'")))
+            .subcommand(Command::new("gettxoutproof")
+                .about("get a merkle proof that a transaction is included in a block")
+                .arg(arg!(<TXID>"'The transaction id to prove'")))
+            .subcommand(Command::new("verifytxoutproof")
+                .about("verify a merkle proof produced by gettxoutproof")
+                .arg(arg!(<PROOF>"'The proof in JSON form'")))
             .get_matches();
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
+        let data_dir = match matches.get_one::<String>("datadir") {
+            Some(dir) => DataDir::new(dir),
+            None => DataDir::default(),
+        };
+        let config = if matches.get_flag("regtest") {
+            NodeConfig::regtest(data_dir)
+        } else {
+            NodeConfig::new(data_dir)
+        };
+
+        if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let address = String::from(address);
-                let bc = Blockchain::create_blockchain(address.clone())?;
+                let bc = Blockchain::create_blockchain(address.clone(), &config)?;
                 let utxo_set = UTXOSet {blockchain: bc};
                 utxo_set.reindex()?;
                 println!("create blockchain");
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let pub_key_hash = Address::decode(address).unwrap().body;
-                let bc = Blockchain::new()?;
-                // let utxos = bc.find_utxo(&pub_key_hash);
+                let script_pubkey = script_for_address(address)?;
+                let bc = Blockchain::new(&config)?;
                 let utxo_set = UTXOSet {blockchain: bc};
-                let utxos = utxo_set.find_utxo(&pub_key_hash)?;
+                let utxos = utxo_set.find_utxo(&script_pubkey)?;
 
-                let mut balance: i32 = 0;
-                for out in utxos.outputs {
-                    balance += out.value;
-                }
+                let balance = Amount::checked_sum(utxos.iter().map(|out| out.value))
+                    .ok_or_else(|| ChainError::InvalidAmount(format!("balance of '{}' is out of range", address)))?;
                 println!("Balance of '{}'; {}", address, balance)
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.get_one::<String>("FROM") {
                 address
             } else {
@@ -87,40 +161,164 @@ impl Cli {
                 exit(1)
             };
 
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+            let amount: Amount = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
                 amount.parse()?
             } else {
                 println!("from not supply!: usage");
                 exit(1)
             };
 
-            let  bc = Blockchain::new()?;
+            let  bc = Blockchain::new(&config)?;
             let mut utxo_set = UTXOSet {blockchain:bc};
-            let tx = Transaction::new_utxo(from, to, amount, &utxo_set)?;
-            let cbtx = Transaction::new_coinbase(from.to_string(), String::from("Reawad!"))?;
-            let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+            let mut mempool = Mempool::load(&utxo_set.blockchain)?;
+            let wallets = Wallets::new(&config.data_dir)?;
+            let tx = match matches.get_one::<String>("feerate") {
+                Some(rate) => Transaction::new_utxo_with_feerate(from, to, amount, rate.parse()?, &wallets, &utxo_set, &mempool)?,
+                None => {
+                    let fee: Amount = match matches.get_one::<String>("fee") {
+                        Some(fee) => fee.parse()?,
+                        None => Amount::ZERO,
+                    };
+                    Transaction::new_utxo(from, to, amount, fee, &wallets, &utxo_set, &mempool)?
+                }
+            };
+            let txid = tx.id.clone();
+            mempool.accept(tx, &utxo_set.blockchain)?;
+            if matches.get_flag("mine") {
+                mine_block(&mut utxo_set.blockchain, &mut mempool, from)?;
+            }
+            println!("success! txid {}", txid);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createhtlc") {
+            let from = required_arg(matches, "FROM")?;
+            let to = required_arg(matches, "TO")?;
+            let amount: Amount = parse_arg(matches, "AMOUNT")?;
+            let hash = parse_hex(required_arg(matches, "HASH")?)?;
+            let timeout: u64 = parse_arg(matches, "TIMEOUT")?;
+            let fee: Amount = match matches.get_one::<String>("fee") {
+                Some(fee) => fee.parse()?,
+                None => Amount::ZERO,
+            };
+
+            let bc = Blockchain::new(&config)?;
+            let mut utxo_set = UTXOSet {blockchain: bc};
+            let mut mempool = Mempool::load(&utxo_set.blockchain)?;
+            let wallets = Wallets::new(&config.data_dir)?;
+            let script_pubkey = htlc_script(&hash, to, from, timeout)?;
+            let tx = Transaction::new_to_script(from, script_pubkey, amount, fee, &wallets, &utxo_set, &mempool)?;
+            let txid = tx.id.clone();
+            mempool.accept(tx, &utxo_set.blockchain)?;
+            if matches.get_flag("mine") {
+                mine_block(&mut utxo_set.blockchain, &mut mempool, from)?;
+            }
+            println!("success! contract {}:0", txid);
+        }
 
-            utxo_set.update(&new_block)?;
-            println!("success!");
+        for command in ["claimhtlc", "refundhtlc"] {
+            if let Some(matches) = matches.subcommand_matches(command) {
+                let txid = required_arg(matches, "TXID")?;
+                let vout: i32 = parse_arg(matches, "VOUT")?;
+                let to = required_arg(matches, "TO")?;
+                let preimage = match matches.try_get_one::<String>("PREIMAGE") {
+                    Ok(Some(preimage)) => Some(parse_hex(preimage)?),
+                    _ => None,
+                };
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => Amount::ZERO,
+                };
+
+                let mut bc = Blockchain::new(&config)?;
+                let mut mempool = Mempool::load(&bc)?;
+                let wallets = Wallets::new(&config.data_dir)?;
+                let prev_tx = match mempool.get(txid) {
+                    Some(prev_tx) => prev_tx.clone(),
+                    None => bc.find_transaction(txid)?,
+                };
+                let tx = Transaction::new_htlc_spend(&prev_tx, vout, preimage.as_deref(), to, fee, &wallets)?;
+                let txid = tx.id.clone();
+                mempool.accept(tx, &bc)?;
+                if matches.get_flag("mine") {
+                    mine_block(&mut bc, &mut mempool, to)?;
+                }
+                println!("success! txid {}", txid);
+            }
         }
 
+        if let Some(matches) = matches.subcommand_matches("senddata") {
+            let from = required_arg(matches, "FROM")?;
+            let data = parse_hex(required_arg(matches, "HEX")?)?;
+            let fee: Amount = match matches.get_one::<String>("fee") {
+                Some(fee) => fee.parse()?,
+                None => Amount::ZERO,
+            };
+
+            let bc = Blockchain::new(&config)?;
+            let mut utxo_set = UTXOSet {blockchain: bc};
+            let mut mempool = Mempool::load(&utxo_set.blockchain)?;
+            let wallets = Wallets::new(&config.data_dir)?;
+            let tx = Transaction::new_data(from, &data, fee, &wallets, &utxo_set, &mempool)?;
+            let txid = tx.id.clone();
+            mempool.accept(tx, &utxo_set.blockchain)?;
+            if matches.get_flag("mine") {
+                mine_block(&mut utxo_set.blockchain, &mut mempool, from)?;
+            }
+            println!("success! txid {}", txid);
+        }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            let bc = Blockchain::new()?;
+        if let Some(matches) = matches.subcommand_matches("getdata") {
+            let txid = required_arg(matches, "TXID")?;
+            let bc = Blockchain::new(&config)?;
+            let tx = match Mempool::load(&bc)?.get(txid) {
+                Some(tx) => tx.clone(),
+                None => bc.find_transaction(txid)?,
+            };
+            let data: Vec<&[u8]> = tx.vout.iter().filter_map(|out| out.script_pubkey.data()).collect();
+            if data.is_empty() {
+                return Err(ChainError::NotFound(format!("data output in transaction {}", txid)));
+            }
+            for data in data {
+                println!("{}", data.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("mine") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let count: usize = matches.get_one::<String>("COUNT").unwrap().parse()?;
+                let mut bc = Blockchain::new(&config)?;
+                let mut mempool = Mempool::load(&bc)?;
+                for _ in 0..count {
+                    let block = mine_block(&mut bc, &mut mempool, address)?;
+                    println!("mined block {} with {} transactions", block.get_hash(), block.get_transaction().len());
+                }
+            }
+        }
+
+        if matches.subcommand_matches("getrawmempool").is_some() {
+            let bc = Blockchain::new(&config)?;
+            for txid in Mempool::load(&bc)?.txids() {
+                println!("{}", txid);
+            }
+        }
+
+
+        if matches.subcommand_matches("printchain").is_some() {
+            let bc = Blockchain::new(&config)?;
             for b in &mut bc.iter() {
                 println!("block: {:#?}", b);
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            let mut ws = Wallets::new()?;
+        if matches.subcommand_matches("createwallet").is_some() {
+            let mut ws = Wallets::new(&config.data_dir)?;
             let address = ws.create_wallet();
             ws.save_all()?;
             println!("success: address {}", address);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
-            let  ws = Wallets::new()?;
+        if matches.subcommand_matches("listaddresses").is_some() {
+            let  ws = Wallets::new(&config.data_dir)?;
             let addresses = ws.get_all_address();
             println!("addresses:");
             for ad in addresses {
@@ -128,8 +326,8 @@ impl Cli {
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("reindex") {
-            let bc = Blockchain::new()?;
+        if matches.subcommand_matches("reindex").is_some() {
+            let bc = Blockchain::new(&config)?;
             let utxo_set = UTXOSet {blockchain: bc};
             utxo_set.reindex()?;
             let count = utxo_set.count_transaction()?;
@@ -137,7 +335,81 @@ impl Cli {
         }
     
 
+        if matches.subcommand_matches("gettotalsupply").is_some() {
+            let bc = Blockchain::new(&config)?;
+            println!("{} of at most {}", bc.get_total_supply()?, config.params.max_supply());
+        }
+
+        if matches.subcommand_matches("getblockcount").is_some() {
+            let bc = Blockchain::new(&config)?;
+            println!("{}", bc.get_best_height()?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblockhash") {
+            if let Some(height) = matches.get_one::<String>("HEIGHT") {
+                let height: usize = height.parse()?;
+                let bc = Blockchain::new(&config)?;
+                println!("{}", bc.get_block_hash(height)?);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettxoutproof") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                let bc = Blockchain::new(&config)?;
+                let proof = bc.get_txout_proof(txid)?;
+                println!("{}", serde_json::to_string(&proof)?);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifytxoutproof") {
+            if let Some(proof) = matches.get_one::<String>("PROOF") {
+                let proof: TxOutProof = serde_json::from_str(proof)?;
+                let txids = proof.verify(&config.params)?;
+                let bc = Blockchain::new(&config)?;
+                let block_hash = proof.block_hash()?;
+                // 侧链上的区块也会保存下来，只有高度索引中的区块才在主链上
+                if bc.store().get_block_hash(proof.header.height)?.as_deref() != Some(block_hash.as_str()) {
+                    println!("proof is valid but block {} is not in the main chain", block_hash);
+                    exit(1)
+                }
+                for txid in txids {
+                    println!("{}", txid);
+                }
+            }
+        }
+
         Ok(())
     }
 
-}
\ No newline at end of file
+}
+
+// 读取必需的参数，缺少时返回错误而不是 panic
+fn required_arg<'a>(matches: &'a ArgMatches, name: &str) -> Result<&'a str> {
+    matches
+        .get_one::<String>(name)
+        .map(String::as_str)
+        .ok_or_else(|| ChainError::InvalidInput(format!("missing argument {}", name)))
+}
+
+// 解析必需的参数，格式错误时返回带参数名的 InvalidInput
+fn parse_arg<T>(matches: &ArgMatches, name: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = required_arg(matches, name)?;
+    value
+        .parse()
+        .map_err(|e| ChainError::InvalidInput(format!("invalid {} '{}': {}", name, value, e)))
+}
+
+// 解析十六进制字符串，例如哈希锁和原像
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ChainError::InvalidInput(format!("invalid hex string: {}", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
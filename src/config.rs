@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use crate::amount::Amount;
+
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+// 节点数据目录，所有存储位置都从同一个根目录派生
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    pub fn new<P: AsRef<Path>>(root: P) -> DataDir {
+        DataDir {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // 区块、UTXO 集、撤销记录和交易池保存在同一个 sled 数据库的不同分区中，见 storage::Column
+    pub fn chainstate(&self) -> PathBuf {
+        self.root.join("chainstate")
+    }
+
+    pub fn wallets(&self) -> PathBuf {
+        self.root.join("wallets")
+    }
+
+    pub fn subdir(&self, name: &str) -> DataDir {
+        DataDir::new(self.root.join(name))
+    }
+}
+
+impl Default for DataDir {
+    fn default() -> Self {
+        DataDir::new(DEFAULT_DATA_DIR)
+    }
+}
+
+// 共识参数，不同的链实例（主网、回归测试网）使用不同的参数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub genesis_bits: u32, // 创世区块的难度
+    pub pow_limit_bits: u32, // 允许的最低难度
+    pub target_spacing_ms: u128, // 期望的出块间隔
+    pub retarget_interval: usize, // 每隔多少个区块调整一次难度
+    pub pow_no_retargeting: bool, // 关闭难度调整
+    pub initial_subsidy: Amount, // 创世区块开始的区块奖励
+    pub subsidy_halving_interval: usize, // 每隔多少个区块奖励减半
+    pub coinbase_maturity: usize, // coinbase 输出需要经过多少个区块才能花费
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            genesis_bits: 0x1f00ffff,
+            pow_limit_bits: 0x2000ffff,
+            target_spacing_ms: 10_000,
+            retarget_interval: 20,
+            pow_no_retargeting: false,
+            initial_subsidy: Amount::from_coins(100),
+            subsidy_halving_interval: 105_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    // 几乎没有难度的本地测试链，区块可以瞬间挖出
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            genesis_bits: 0x207fffff,
+            pow_limit_bits: 0x207fffff,
+            target_spacing_ms: 10_000,
+            retarget_interval: 20,
+            pow_no_retargeting: true,
+            initial_subsidy: Amount::from_coins(100),
+            subsidy_halving_interval: 150,
+            coinbase_maturity: 100,
+        }
+    }
+
+    pub fn target_timespan_ms(&self) -> u128 {
+        self.target_spacing_ms * self.retarget_interval as u128
+    }
+
+    // 按减半规则最终能发行的总量上限
+    pub fn max_supply(&self) -> Amount {
+        let mut supply: u128 = 0;
+        let mut subsidy = self.initial_subsidy.as_units();
+        while subsidy > 0 {
+            supply += subsidy as u128 * self.subsidy_halving_interval as u128;
+            subsidy >>= 1;
+        }
+        Amount::from_units(supply.min(u64::MAX as u128) as u64)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::main()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NodeConfig {
+    pub data_dir: DataDir,
+    pub params: ChainParams,
+}
+
+impl NodeConfig {
+    pub fn new(data_dir: DataDir) -> NodeConfig {
+        NodeConfig {
+            data_dir,
+            params: ChainParams::main(),
+        }
+    }
+
+    // 回归测试链的数据保存在数据目录下的 regtest 子目录中，与主网互不干扰
+    pub fn regtest(data_dir: DataDir) -> NodeConfig {
+        NodeConfig {
+            data_dir: data_dir.subdir("regtest"),
+            params: ChainParams::regtest(),
+        }
+    }
+}
+
+// 内存中测试链使用的配置，coinbase 输出可以立即花费，方便构造转账
+#[cfg(test)]
+pub(crate) fn spendable_config() -> NodeConfig {
+    let mut config = NodeConfig::regtest(DataDir::default());
+    config.params.coinbase_maturity = 0;
+    config
+}
+
+// 为测试创建一个独立的临时数据目录，避免测试之间互相干扰
+#[cfg(test)]
+pub(crate) fn temp_config(name: &str) -> NodeConfig {
+    use std::time::SystemTime;
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let root = std::env::temp_dir().join(format!(
+        "blockchain_rust_{}_{}_{}",
+        name,
+        std::process::id(),
+        nanos
+    ));
+    NodeConfig::regtest(DataDir::new(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_derive_from_root() {
+        let dir = DataDir::new("/tmp/node1");
+        assert_eq!(dir.chainstate(), PathBuf::from("/tmp/node1/chainstate"));
+        assert_eq!(dir.wallets(), PathBuf::from("/tmp/node1/wallets"));
+        assert_eq!(DataDir::default().root(), Path::new(DEFAULT_DATA_DIR));
+    }
+}
@@ -1 +1,119 @@
-pub type Result<T> = std::result::Result<T, failure::Error>;
+use std::fmt;
+use std::io;
+
+use crate::amount::Amount;
+use crate::mempool::MempoolRejection;
+use crate::validation::BlockRejection;
+
+pub type Result<T> = std::result::Result<T, ChainError>;
+
+// 库中所有操作返回的错误，调用方可以直接匹配具体的失败原因
+#[derive(Debug)]
+pub enum ChainError {
+    Storage(String), // 存储后端出错，或者存储中的数据缺失、损坏
+    Serialization(String), // 序列化或反序列化失败
+    Io(io::Error),
+    Validation(BlockRejection), // 区块或交易没有通过共识检查
+    Mempool(MempoolRejection), // 交易被交易池拒绝
+    Wallet(String), // 钱包不存在或无法使用
+    InvalidAddress(String),
+    InvalidAmount(String),
+    InsufficientFunds { needed: Amount, available: Amount },
+    NotFound(String), // 查询的区块或交易不存在
+    InvalidInput(String), // 其他无效的参数，例如格式错误的哈希或 Merkle 证明
+}
+
+impl ChainError {
+    // 共识检查失败时返回具体原因
+    pub fn rejection(&self) -> Option<&BlockRejection> {
+        match self {
+            ChainError::Validation(rejection) => Some(rejection),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::Storage(msg) => write!(f, "storage error: {}", msg),
+            ChainError::Serialization(msg) => write!(f, "serialization error: {}", msg),
+            ChainError::Io(e) => write!(f, "io error: {}", e),
+            ChainError::Validation(rejection) => write!(f, "{} [{}]", rejection, rejection.code()),
+            ChainError::Mempool(rejection) => write!(f, "{} [{}]", rejection, rejection.code()),
+            ChainError::Wallet(msg) => write!(f, "wallet error: {}", msg),
+            ChainError::InvalidAddress(address) => write!(f, "invalid address: {}", address),
+            ChainError::InvalidAmount(msg) => write!(f, "invalid amount: {}", msg),
+            ChainError::InsufficientFunds { needed, available } => {
+                write!(f, "not enough balance: need {} but only {} is spendable", needed, available)
+            }
+            ChainError::NotFound(what) => write!(f, "{} not found", what),
+            ChainError::InvalidInput(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChainError::Io(e) => Some(e),
+            ChainError::Validation(rejection) => Some(rejection),
+            ChainError::Mempool(rejection) => Some(rejection),
+            _ => None,
+        }
+    }
+}
+
+impl From<BlockRejection> for ChainError {
+    fn from(rejection: BlockRejection) -> Self {
+        ChainError::Validation(rejection)
+    }
+}
+
+impl From<MempoolRejection> for ChainError {
+    fn from(rejection: MempoolRejection) -> Self {
+        ChainError::Mempool(rejection)
+    }
+}
+
+impl From<sled::Error> for ChainError {
+    fn from(e: sled::Error) -> Self {
+        ChainError::Storage(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for ChainError {
+    fn from(e: bincode::Error) -> Self {
+        ChainError::Serialization(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ChainError {
+    fn from(e: serde_json::Error) -> Self {
+        ChainError::Serialization(e.to_string())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for ChainError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        ChainError::Serialization(e.to_string())
+    }
+}
+
+impl From<io::Error> for ChainError {
+    fn from(e: io::Error) -> Self {
+        ChainError::Io(e)
+    }
+}
+
+impl From<std::time::SystemTimeError> for ChainError {
+    fn from(e: std::time::SystemTimeError) -> Self {
+        ChainError::Io(io::Error::other(e))
+    }
+}
+
+impl From<std::num::ParseIntError> for ChainError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        ChainError::InvalidInput(e.to_string())
+    }
+}
@@ -1,8 +1,18 @@
+pub mod amount;
 pub mod block;
 pub mod errors;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod pow;
 pub mod blockchain;
 pub mod cli;
+pub mod config;
+pub mod script;
+pub mod sigcache;
+pub mod storage;
 pub mod transaction;
 pub mod tx;
 pub mod wallet;
-pub mod utxoset;
\ No newline at end of file
+pub mod utxoset;
+pub mod validation;
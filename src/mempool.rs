@@ -0,0 +1,510 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainUpdate};
+use crate::errors::{ChainError, Result};
+use crate::storage::WriteBatch;
+use crate::transaction::Transaction;
+use crate::tx::TXOutput;
+use crate::validation::{check_transaction, check_tx_inputs};
+
+// 交易被交易池拒绝的原因，输入和签名相关的错误沿用 BlockRejection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolRejection {
+    Coinbase(String),
+    AlreadyKnown(String),
+    Conflict { txid: String, prev_txid: String, vout: i32, spent_by: String },
+}
+
+impl MempoolRejection {
+    // 稳定的拒绝原因代码，便于程序匹配和记录日志
+    pub fn code(&self) -> &'static str {
+        match self {
+            MempoolRejection::Coinbase(_) => "coinbase",
+            MempoolRejection::AlreadyKnown(_) => "txn-already-known",
+            MempoolRejection::Conflict { .. } => "txn-mempool-conflict",
+        }
+    }
+}
+
+impl fmt::Display for MempoolRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolRejection::Coinbase(txid) => write!(f, "coinbase transaction {} cannot enter the mempool", txid),
+            MempoolRejection::AlreadyKnown(txid) => write!(f, "transaction {} is already known", txid),
+            MempoolRejection::Conflict { txid, prev_txid, vout, spent_by } => write!(
+                f,
+                "transaction {} spends output {}:{} already spent by {}",
+                txid, prev_txid, vout, spent_by
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MempoolRejection {}
+
+// 交易池中的一笔交易，以及接受时计算出的手续费和大小
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: Amount,
+    pub size: usize,
+}
+
+impl MempoolEntry {
+    // 每 1000 字节的手续费，以最小单位计
+    pub fn fee_rate(&self) -> u64 {
+        (self.fee.as_units() as u128 * 1000 / self.size.max(1) as u128) as u64
+    }
+}
+
+// 尚未打包进区块的交易，与链状态保存在同一个存储中
+#[derive(Debug, Default)]
+pub struct Mempool {
+    txs: BTreeMap<String, MempoolEntry>,
+    // 被交易池中的交易花费的输出 -> 花费它的交易 id
+    spends: HashMap<(String, i32), String>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    pub fn load(bc: &Blockchain) -> Result<Mempool> {
+        let mut mempool = Mempool::new();
+        for entry in bc.store().mempool_entries()? {
+            mempool.insert(entry);
+        }
+        Ok(mempool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.txs.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.txs.get(txid).map(|entry| &entry.tx)
+    }
+
+    pub fn get_entry(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.txs.get(txid)
+    }
+
+    pub fn txids(&self) -> Vec<String> {
+        self.txs.keys().cloned().collect()
+    }
+
+    // 输出是否已被交易池中的某笔交易花费
+    pub fn is_spent(&self, txid: &str, vout: i32) -> bool {
+        self.spends.contains_key(&(txid.to_string(), vout))
+    }
+
+    // 交易池中属于该公钥哈希且尚未被花费的输出，可以用来构造连续的未确认交易
+    pub fn find_unspent(&self, pub_key_hash: &[u8]) -> Vec<(String, i32, TXOutput)> {
+        let mut outputs = Vec::new();
+        for (txid, entry) in &self.txs {
+            for (index, out) in entry.tx.vout.iter().enumerate() {
+                if out.can_be_unlock_with(pub_key_hash) && !self.is_spent(txid, index as i32) {
+                    outputs.push((txid.clone(), index as i32, out.clone()));
+                }
+            }
+        }
+        outputs
+    }
+
+    // 校验交易并加入交易池：输入必须来自 UTXO 集或交易池中的其他交易，且没有被交易池中的交易花费
+    pub fn accept(&mut self, tx: Transaction, bc: &Blockchain) -> Result<()> {
+        if tx.is_coinbase() {
+            return Err(MempoolRejection::Coinbase(tx.id.clone()).into());
+        }
+        if self.contains(&tx.id) || bc.store().get_utxos(&tx.id)?.is_some() {
+            return Err(MempoolRejection::AlreadyKnown(tx.id.clone()).into());
+        }
+        check_transaction(&tx)?;
+        for vin in &tx.vin {
+            if let Some(spent_by) = self.spends.get(&(vin.txid.clone(), vin.vout)) {
+                return Err(MempoolRejection::Conflict {
+                    txid: tx.id.clone(),
+                    prev_txid: vin.txid.clone(),
+                    vout: vin.vout,
+                    spent_by: spent_by.clone(),
+                }
+                .into());
+            }
+        }
+        let created: HashMap<String, &Transaction> =
+            self.txs.iter().map(|(id, entry)| (id.clone(), &entry.tx)).collect();
+        let fee = check_tx_inputs(&tx, bc, &created, &bc.get_tip_header()?)?;
+
+        let entry = MempoolEntry {
+            size: tx.size()?,
+            tx,
+            fee,
+        };
+        let mut batch = WriteBatch::new();
+        batch.put_mempool_entry(&entry)?;
+        bc.store().write(batch)?;
+        info!("accepted transaction {} into mempool with fee {}", entry.tx.id, entry.fee);
+        self.insert(entry);
+        Ok(())
+    }
+
+    // 选择总大小不超过 max_size 的交易用于打包，优先选择手续费率高的交易；
+    // 父交易总是排在花费它的子交易之前，父交易没有被选中时子交易也不会被选中
+    pub fn select_transactions(&self, max_size: usize) -> Vec<&MempoolEntry> {
+        let mut selected: Vec<&MempoolEntry> = Vec::new();
+        let mut included: HashSet<&str> = HashSet::new();
+        let mut skipped: HashSet<&str> = HashSet::new();
+        let mut size = 0;
+        loop {
+            let ready = self
+                .txs
+                .values()
+                .filter(|entry| !included.contains(entry.tx.id.as_str()) && !skipped.contains(entry.tx.id.as_str()))
+                .filter(|entry| {
+                    entry
+                        .tx
+                        .vin
+                        .iter()
+                        .all(|vin| !self.contains(&vin.txid) || included.contains(vin.txid.as_str()))
+                })
+                .max_by_key(|entry| entry.fee_rate());
+            let entry = match ready {
+                Some(entry) => entry,
+                None => break,
+            };
+            if size + entry.size > max_size {
+                skipped.insert(&entry.tx.id);
+                continue;
+            }
+            size += entry.size;
+            included.insert(&entry.tx.id);
+            selected.push(entry);
+        }
+        selected
+    }
+
+    // 对照当前链尖按父交易在前的顺序重新检查交易池中的交易，移除不再有效的交易及其后代，返回被移除的交易 id。
+    // 链重组之后交易花费的输出可能已经不存在或者被花费，花费的 coinbase 也可能还不成熟
+    pub fn revalidate(&mut self, bc: &Blockchain) -> Result<Vec<String>> {
+        let ordered: Vec<Transaction> =
+            self.select_transactions(usize::MAX).into_iter().map(|entry| entry.tx.clone()).collect();
+        let tip = bc.get_tip_header()?;
+        let mut created: HashMap<String, &Transaction> = HashMap::new();
+        let mut removed = Vec::new();
+        for tx in &ordered {
+            // 父交易被移除时已经一起移除
+            if !self.contains(&tx.id) {
+                continue;
+            }
+            match check_tx_inputs(tx, bc, &created, &tip) {
+                Ok(_) => {
+                    created.insert(tx.id.clone(), tx);
+                }
+                Err(err) if err.rejection().is_some() => {
+                    info!("evicting transaction {} from mempool: {}", tx.id, err);
+                    self.remove_with_descendants(&tx.id, &mut removed);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for txid in &removed {
+            batch.delete_mempool_tx(txid);
+        }
+        bc.store().write(batch)?;
+        Ok(removed)
+    }
+
+    // 区块上链后移除其中的交易，以及与区块中的交易冲突的交易和它们的后代
+    pub fn remove_for_block(&mut self, block: &Block, bc: &Blockchain) -> Result<()> {
+        let mut removed = Vec::new();
+        for tx in block.get_transaction() {
+            if self.txs.contains_key(&tx.id) {
+                self.remove(&tx.id, &mut removed);
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(spent_by) = self.spends.get(&(vin.txid.clone(), vin.vout)).cloned() {
+                    self.remove_with_descendants(&spent_by, &mut removed);
+                }
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for txid in &removed {
+            batch.delete_mempool_tx(txid);
+        }
+        bc.store().write(batch)
+    }
+
+    // 主链变化之后更新交易池：移除新连接区块中已确认和冲突的交易，以及对照新链尖不再有效的交易，
+    // 再按原来的顺序放回断开区块中的交易，已经在新主链上或者与之冲突的交易不会被接受
+    pub fn apply_chain_update(&mut self, update: &ChainUpdate, bc: &Blockchain) -> Result<()> {
+        for block in &update.connected {
+            self.remove_for_block(block, bc)?;
+        }
+        if update.disconnected.is_empty() {
+            return Ok(());
+        }
+        self.revalidate(bc)?;
+        for block in update.disconnected.iter().rev() {
+            for tx in &block.get_transaction()[1..] {
+                match self.accept(tx.clone(), bc) {
+                    Ok(()) => {}
+                    Err(err @ (ChainError::Validation(_) | ChainError::Mempool(_))) => {
+                        info!("transaction {} from disconnected block is not resubmitted: {}", tx.id, err);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, entry: MempoolEntry) {
+        for vin in &entry.tx.vin {
+            self.spends.insert((vin.txid.clone(), vin.vout), entry.tx.id.clone());
+        }
+        self.txs.insert(entry.tx.id.clone(), entry);
+    }
+
+    fn remove(&mut self, txid: &str, removed: &mut Vec<String>) {
+        if let Some(entry) = self.txs.remove(txid) {
+            for vin in &entry.tx.vin {
+                self.spends.remove(&(vin.txid.clone(), vin.vout));
+            }
+            removed.push(txid.to_string());
+        }
+    }
+
+    fn remove_with_descendants(&mut self, txid: &str, removed: &mut Vec<String>) {
+        let outputs = match self.txs.get(txid) {
+            Some(entry) => entry.tx.vout.len(),
+            None => return,
+        };
+        self.remove(txid, removed);
+        for vout in 0..outputs {
+            if let Some(child) = self.spends.get(&(txid.to_string(), vout as i32)).cloned() {
+                self.remove_with_descendants(&child, removed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::spendable_config;
+    use crate::errors::ChainError;
+    use crate::miner::mine_block;
+    use crate::transaction::SUBSIDY;
+    use crate::script::Script;
+    use crate::tx::{TXInput, SEQUENCE_FINAL};
+    use crate::validation::{BlockRejection, MAX_BLOCK_SIZE};
+    use crate::wallet::Wallet;
+
+    fn spend(from: &Wallet, prev: &Transaction, vout: i32, outputs: &[(&Wallet, u64)]) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: outputs
+                .iter()
+                .map(|(to, value)| TXOutput::new(Amount::from_coins(*value), to.get_address()).unwrap())
+                .collect(),
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        tx.id = tx.hash().unwrap();
+        tx.sign(&from.secret_key, HashMap::from([(prev.id.clone(), prev.clone())])).unwrap();
+        tx
+    }
+
+    fn mempool_rejection(res: Result<()>) -> MempoolRejection {
+        match res.unwrap_err() {
+            ChainError::Mempool(rejection) => rejection,
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_accept_and_mine() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut mempool = Mempool::new();
+
+        let pay = spend(&alice, &genesis_cb, 0, &[(&bob, 60), (&alice, 40)]);
+        mempool.accept(pay.clone(), &bc).unwrap();
+        assert!(mempool.is_spent(&genesis_cb.id, 0));
+
+        // 与交易池中的交易花费同一个输出
+        let double = spend(&alice, &genesis_cb, 0, &[(&alice, 100)]);
+        assert_eq!(
+            mempool_rejection(mempool.accept(double.clone(), &bc)),
+            MempoolRejection::Conflict {
+                txid: double.id,
+                prev_txid: genesis_cb.id.clone(),
+                vout: 0,
+                spent_by: pay.id.clone(),
+            }
+        );
+        assert_eq!(mempool_rejection(mempool.accept(pay.clone(), &bc)), MempoolRejection::AlreadyKnown(pay.id.clone()));
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("cb"), 1).unwrap();
+        assert!(matches!(mempool_rejection(mempool.accept(cbtx, &bc)), MempoolRejection::Coinbase(_)));
+
+        // 花费交易池中尚未确认的输出
+        let child = spend(&bob, &pay, 0, &[(&alice, 60)]);
+        mempool.accept(child.clone(), &bc).unwrap();
+        let theft = spend(&bob, &pay, 1, &[(&bob, 40)]);
+        let err = mempool.accept(theft, &bc).unwrap_err();
+        assert!(matches!(err.rejection(), Some(BlockRejection::InvalidSignature(_))));
+        assert_eq!(Mempool::load(&bc).unwrap().len(), 2);
+
+        // 打包时父交易排在子交易之前，上链后交易池清空
+        let block = mine_block(&mut bc, &mut mempool, &bob.get_address()).unwrap();
+        let ids: Vec<String> = block.get_transaction()[1..].iter().map(|tx| tx.id.clone()).collect();
+        assert_eq!(ids, vec![pay.id, child.id]);
+        assert!(mempool.is_empty());
+        assert!(Mempool::load(&bc).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_select_by_fee_rate() {
+        let alice = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let cb0 = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+        let cb1 = bc.add_block(vec![cbtx]).unwrap().get_transaction()[0].clone();
+        let mut mempool = Mempool::new();
+
+        // 低手续费的父交易、高手续费的子交易和一笔独立的中等手续费交易
+        let parent = spend(&alice, &cb0, 0, &[(&alice, 90)]);
+        let child = spend(&alice, &parent, 0, &[(&alice, 0)]);
+        let other = spend(&alice, &cb1, 0, &[(&alice, 50)]);
+        for tx in [&parent, &child, &other] {
+            mempool.accept(tx.clone(), &bc).unwrap();
+        }
+        assert_eq!(mempool.get_entry(&child.id).unwrap().fee, Amount::from_coins(90));
+
+        let ids = |entries: Vec<&MempoolEntry>| entries.iter().map(|e| e.tx.id.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            ids(mempool.select_transactions(MAX_BLOCK_SIZE)),
+            vec![other.id.clone(), parent.id.clone(), child.id.clone()]
+        );
+        // 空间不足时只保留手续费率最高的交易，子交易不会脱离父交易被选中
+        let room = mempool.get_entry(&other.id).unwrap().size;
+        assert_eq!(ids(mempool.select_transactions(room)), vec![other.id.clone()]);
+
+        // coinbase 领取区块奖励和全部手续费
+        let block = mine_block(&mut bc, &mut mempool, &alice.get_address()).unwrap();
+        assert_eq!(block.get_transaction()[0].vout[0].value, SUBSIDY.checked_add(Amount::from_coins(150)).unwrap());
+    }
+
+    #[test]
+    fn test_remove_conflicts() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut mempool = Mempool::new();
+
+        let pay = spend(&alice, &genesis_cb, 0, &[(&bob, 100)]);
+        let child = spend(&bob, &pay, 0, &[(&alice, 100)]);
+        mempool.accept(pay, &bc).unwrap();
+        mempool.accept(child, &bc).unwrap();
+
+        // 区块中另一笔交易花费了同一个输出，交易池中的交易及其后代都被移除
+        let other = spend(&alice, &genesis_cb, 0, &[(&alice, 100)]);
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("cb"), 1).unwrap();
+        let block = bc.add_block(vec![cbtx, other]).unwrap();
+        mempool.remove_for_block(&block, &bc).unwrap();
+        assert!(mempool.is_empty());
+        assert!(Mempool::load(&bc).unwrap().is_empty());
+    }
+
+    // 在 parent 之后挖出一个侧链区块
+    fn mine_on(parent: &Block, data: &str, address: &str, txs: Vec<Transaction>) -> Block {
+        let header = parent.get_header();
+        let cbtx = Transaction::new_coinbase(address.to_string(), data.to_string(), header.height + 1).unwrap();
+        Block::new_block([vec![cbtx], txs].concat(), parent.get_hash(), header.height + 1, header.bits).unwrap()
+    }
+
+    #[test]
+    fn test_reorg_resubmits_transactions() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis = bc.iter().next().unwrap();
+        let pay = spend(&alice, &genesis.get_transaction()[0], 0, &[(&bob, 100)]);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("a1"), 1).unwrap();
+        bc.add_block(vec![cbtx, pay.clone()]).unwrap();
+
+        // 更长的侧链上没有这笔转账，切换后转账回到交易池，之后可以重新打包
+        let b1 = mine_on(&genesis, "b1", &bob.get_address(), vec![]);
+        let b2 = mine_on(&b1, "b2", &bob.get_address(), vec![]);
+        let mut mempool = Mempool::load(&bc).unwrap();
+        let update = bc.process_block(&b1).unwrap();
+        assert!(update.connected.is_empty() && update.disconnected.is_empty());
+        let update = bc.process_block(&b2).unwrap();
+        assert_eq!(bc.tip(), b2.get_hash());
+        assert_eq!(update.disconnected.len(), 1);
+        assert_eq!(update.connected.iter().map(|block| block.get_hash()).collect::<Vec<_>>(), vec![b1.get_hash(), b2.get_hash()]);
+        mempool.apply_chain_update(&update, &bc).unwrap();
+        assert_eq!(mempool.txids(), vec![pay.id.clone()]);
+        assert_eq!(Mempool::load(&bc).unwrap().txids(), vec![pay.id.clone()]);
+        let block = mine_block(&mut bc, &mut mempool, &bob.get_address()).unwrap();
+        assert_eq!(block.get_transaction()[1].id, pay.id);
+    }
+
+    #[test]
+    fn test_template_evicts_invalid() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis = bc.iter().next().unwrap();
+        let genesis_cb = genesis.get_transaction()[0].clone();
+        let pay = spend(&alice, &genesis_cb, 0, &[(&bob, 100)]);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("a1"), 1).unwrap();
+        bc.add_block(vec![cbtx, pay.clone()]).unwrap();
+        let mut mempool = Mempool::new();
+        let child = spend(&bob, &pay, 0, &[(&alice, 100)]);
+        mempool.accept(child.clone(), &bc).unwrap();
+
+        // 侧链花费了同一个输出，切换后转账和交易池中花费它的交易都不再有效
+        let other = spend(&alice, &genesis_cb, 0, &[(&alice, 100)]);
+        let b1 = mine_on(&genesis, "b1", &bob.get_address(), vec![other]);
+        let b2 = mine_on(&b1, "b2", &bob.get_address(), vec![]);
+        bc.process_block(&b1).unwrap();
+        bc.process_block(&b2).unwrap();
+
+        // 没有按主链变化更新的交易池仍然保留着这笔交易，组装区块时被移除，挖矿不会一直失败
+        assert!(mempool.contains(&child.id));
+        let block = mine_block(&mut bc, &mut mempool, &bob.get_address()).unwrap();
+        assert_eq!(block.get_transaction().len(), 1);
+        assert!(mempool.is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use crypto::{digest::Digest, sha2::Sha256};
+use merkle_cbt::merkle_tree::{Merge, MerkleProof, CBMT};
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, BlockHeader};
+use crate::config::ChainParams;
+use crate::errors::{ChainError, Result};
+use crate::validation::check_block_header;
+
+pub(crate) struct MergeTX{}
+
+impl Merge for MergeTX {
+    type Item = Vec<u8>;
+    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
+        let mut hasher = Sha256::new();
+        let mut data: Vec<u8> = left.clone();
+        data.append(&mut right.clone());
+        hasher.input(&data);
+        let mut re: [u8; 32] = [0; 32];
+        hasher.result(&mut re);
+        re.to_vec()
+    }
+}
+
+// Merkle 树的叶子是交易 id
+fn leaves(txids: &[String]) -> Vec<Vec<u8>> {
+    txids.iter().map(|id| id.as_bytes().to_vec()).collect()
+}
+
+pub fn merkle_root(txids: &[String]) -> Vec<u8> {
+    CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&leaves(txids)).root()
+}
+
+// 证明若干交易包含在某个区块中，只需要区块头即可验证
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxOutProof {
+    pub header: BlockHeader,
+    pub txids: Vec<String>,
+    pub indices: Vec<u32>,
+    pub lemmas: Vec<Vec<u8>>,
+}
+
+impl TxOutProof {
+    pub fn new(block: &Block, txids: &[String]) -> Result<TxOutProof> {
+        let all: Vec<String> = block.get_transaction().iter().map(|tx| tx.id.clone()).collect();
+        let mut positions = Vec::new();
+        for txid in txids {
+            match all.iter().position(|id| id == txid) {
+                Some(pos) => positions.push(pos as u32),
+                None => return Err(ChainError::NotFound(format!("transaction {} in block {}", txid, block.get_hash()))),
+            }
+        }
+
+        let proof = CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(&leaves(&all), &positions)
+            .ok_or_else(|| ChainError::InvalidInput(String::from("cannot build merkle proof")))?;
+        Ok(TxOutProof {
+            header: block.get_header().clone(),
+            txids: txids.to_vec(),
+            indices: proof.indices().to_vec(),
+            lemmas: proof.lemmas().to_vec(),
+        })
+    }
+
+    pub fn block_hash(&self) -> Result<String> {
+        self.header.hash()
+    }
+
+    // 校验区块头的工作量证明以及证明路径能否还原出区块头中的 Merkle 根，成功时返回被证明的交易 id
+    pub fn verify(&self, params: &ChainParams) -> Result<Vec<String>> {
+        check_block_header(&self.header, params)?;
+        let proof = MerkleProof::<Vec<u8>, MergeTX>::new(self.indices.clone(), self.lemmas.clone());
+        if !proof.verify(&self.header.merkle_root, &leaves(&self.txids)) {
+            return Err(ChainError::InvalidInput(String::from("merkle proof does not match block header")));
+        }
+        Ok(self.txids.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_txout_proof() -> Result<()> {
+        let address = Wallet::new().get_address();
+        let mut txs = Vec::new();
+        for i in 0..5 {
+            txs.push(Transaction::new_coinbase(address.clone(), format!("tx {}", i), 0)?);
+        }
+        let params = ChainParams::regtest();
+        let block = Block::new_block(txs.clone(), String::new(), 0, params.genesis_bits)?;
+
+        let wanted = vec![txs[1].id.clone(), txs[4].id.clone()];
+        let proof = TxOutProof::new(&block, &wanted)?;
+        assert_eq!(proof.block_hash()?, block.get_hash());
+        assert_eq!(proof.verify(&params)?, wanted);
+
+        // 替换成区块外的交易 id 后证明失败
+        let mut forged = proof.clone();
+        forged.txids[0] = Transaction::new_coinbase(address, String::from("other"), 0)?.id;
+        assert!(forged.verify(&params).is_err());
+
+        assert!(TxOutProof::new(&block, &[String::from("missing")]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use log::info;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::{ChainError, Result};
+use crate::mempool::Mempool;
+use crate::transaction::Transaction;
+use crate::validation::{block_subsidy, MAX_BLOCK_SIZE};
+
+// 为区块头和 coinbase 预留的空间
+const COINBASE_RESERVE: usize = 1000;
+
+// 组装下一个区块的交易：先移除交易池中对照当前链尖已经无效的交易，再按手续费率选择交易，
+// coinbase 领取当前高度的区块奖励和全部手续费，并写入额外随机数 extra_nonce
+pub fn create_block_template(
+    bc: &Blockchain,
+    mempool: &mut Mempool,
+    address: &str,
+    data: &str,
+    extra_nonce: u64,
+) -> Result<Vec<Transaction>> {
+    mempool.revalidate(bc)?;
+    let selected = mempool.select_transactions(MAX_BLOCK_SIZE - COINBASE_RESERVE);
+    let height = bc.get_best_height()? + 1;
+    let subsidy = block_subsidy(height, &bc.config().params);
+    let value = Amount::checked_sum(selected.iter().map(|entry| entry.fee).chain([subsidy]))
+        .ok_or_else(|| ChainError::InvalidAmount(format!("block reward at height {} is out of range", height)))?;
+    let cbtx = Transaction::new_coinbase_with_value(address.to_string(), data.to_string(), height, extra_nonce, value)?;
+
+    let mut txs = vec![cbtx];
+    txs.extend(selected.into_iter().map(|entry| entry.tx.clone()));
+    Ok(txs)
+}
+
+// 挖出一个包含交易池交易的区块，上链后从交易池中移除已确认和冲突的交易
+pub fn mine_block(bc: &mut Blockchain, mempool: &mut Mempool, address: &str) -> Result<Block> {
+    let txs = create_block_template(bc, mempool, address, "Reawad!", 0)?;
+    let block = bc.add_block(txs)?;
+    mempool.remove_for_block(&block, bc)?;
+    info!("mined block {} at height {}", block.get_hash(), block.get_header().height);
+    Ok(block)
+}
@@ -0,0 +1,206 @@
+
+use crate::block::BlockHeader;
+use crate::config::ChainParams;
+use crate::errors::{ChainError, Result};
+use crate::storage::ChainStore;
+
+// 256 位目标值，按大端序存储，哈希值不大于目标值即满足工作量证明
+pub type Target = [u8; 32];
+
+// 将紧凑格式（bits）解码为 256 位目标值，负数或溢出时返回 None
+pub fn compact_to_target(bits: u32) -> Option<Target> {
+    let size = (bits >> 24) as usize;
+    let word = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 && word != 0 {
+        return None;
+    }
+
+    let mut target = [0u8; 32];
+    if size <= 3 {
+        let value = word >> (8 * (3 - size));
+        target[29..].copy_from_slice(&value.to_be_bytes()[1..]);
+        return Some(target);
+    }
+
+    let mantissa = word.to_be_bytes();
+    for (i, byte) in mantissa[1..].iter().enumerate() {
+        // 尾数字节在数值中的位置（从最低字节开始计数）
+        let pos = size - 1 - i;
+        if pos >= 32 {
+            if *byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[31 - pos] = *byte;
+    }
+    Some(target)
+}
+
+// 将 256 位目标值编码为紧凑格式
+pub fn target_to_compact(target: &Target) -> u32 {
+    let mut size = 32 - target.iter().take_while(|b| **b == 0).count();
+    let mut compact: u32 = if size <= 3 {
+        let mut bytes = [0u8; 4];
+        bytes[1..].copy_from_slice(&target[29..]);
+        u32::from_be_bytes(bytes) << (8 * (3 - size))
+    } else {
+        let start = 32 - size;
+        u32::from_be_bytes([0, target[start], target[start + 1], target[start + 2]])
+    };
+    // 最高位是符号位，需要时向右移一个字节
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size as u32) << 24
+}
+
+// 将十六进制的哈希字符串转换为 256 位数值
+pub fn hash_to_target(hash: &str) -> Result<Target> {
+    if hash.len() != 64 {
+        return Err(ChainError::InvalidInput(format!("invalid hash length: {}", hash.len())));
+    }
+    let mut target = [0u8; 32];
+    for (i, byte) in target.iter_mut().enumerate() {
+        let digits = hash
+            .get(2 * i..2 * i + 2)
+            .ok_or_else(|| ChainError::InvalidInput(format!("invalid hash: {}", hash)))?;
+        *byte = u8::from_str_radix(digits, 16)?;
+    }
+    Ok(target)
+}
+
+pub fn hash_meets_target(hash: &str, bits: u32) -> Result<bool> {
+    match compact_to_target(bits) {
+        Some(target) => Ok(hash_to_target(hash)? <= target),
+        None => Ok(false),
+    }
+}
+
+// 目标值先乘后除（target * m / d），中间结果使用更宽的缓冲区避免溢出，结果超过 256 位时返回 None
+fn scale_target(target: &Target, m: u128, d: u128) -> Option<Target> {
+    let mut wide = [0u8; 48];
+    let mut carry: u128 = 0;
+    for i in (0..48).rev() {
+        let byte = if i >= 16 { target[i - 16] } else { 0 };
+        let prod = byte as u128 * m + carry;
+        wide[i] = (prod & 0xff) as u8;
+        carry = prod >> 8;
+    }
+
+    let mut rem: u128 = 0;
+    for byte in wide.iter_mut() {
+        let cur = (rem << 8) | *byte as u128;
+        *byte = (cur / d) as u8;
+        rem = cur % d;
+    }
+
+    if wide[..16].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&wide[16..]);
+    Some(out)
+}
+
+// 单个区块的工作量，约等于 2^256 / target，超出 u128 范围时取最大值
+pub fn block_work(bits: u32) -> u128 {
+    let size = (bits >> 24) as i64;
+    let word = (bits & 0x007f_ffff) as u128;
+    if word == 0 || bits & 0x0080_0000 != 0 {
+        return 0;
+    }
+    // target = word * 2^(8 * (size - 3))，因此 work = 2^(256 - 8 * (size - 3)) / word
+    let shift = 256 - 8 * (size - 3);
+    if shift >= 128 {
+        return u128::MAX;
+    }
+    if shift < 0 {
+        return 0;
+    }
+    (1u128 << shift) / word
+}
+
+// 根据父区块计算下一个区块需要的难度，每 retarget_interval 个区块按实际出块时间调整一次
+pub fn next_work_required(store: &dyn ChainStore, parent: &BlockHeader, params: &ChainParams) -> Result<u32> {
+    let height = parent.height + 1;
+    if params.pow_no_retargeting || !height.is_multiple_of(params.retarget_interval) {
+        return Ok(parent.bits);
+    }
+
+    // 找到本轮调整周期的第一个区块
+    let mut first = parent.clone();
+    for _ in 0..params.retarget_interval - 1 {
+        first = store
+            .get_block(&first.prev_block_hash)?
+            .ok_or_else(|| ChainError::Storage(format!("missing ancestor {}", first.prev_block_hash)))?
+            .get_header()
+            .clone();
+    }
+
+    // 限制单次调整幅度在 4 倍以内
+    let expected = params.target_timespan_ms();
+    let actual = parent
+        .timestamp
+        .saturating_sub(first.timestamp)
+        .clamp(expected / 4, expected * 4);
+
+    let limit = compact_to_target(params.pow_limit_bits)
+        .ok_or_else(|| ChainError::InvalidInput(format!("invalid pow limit {:08x}", params.pow_limit_bits)))?;
+    let old = compact_to_target(parent.bits)
+        .ok_or_else(|| ChainError::Storage(format!("invalid bits in parent {:08x}", parent.bits)))?;
+    let new = scale_target(&old, actual, expected).unwrap_or(limit);
+    Ok(target_to_compact(&new.min(limit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_roundtrip() {
+        for bits in [0x1d00ffff, 0x1f00ffff, 0x207fffff, 0x1b0404cb, 0x03123456] {
+            let target = compact_to_target(bits).unwrap();
+            assert_eq!(target_to_compact(&target), bits);
+        }
+
+        let target = compact_to_target(0x1f00ffff).unwrap();
+        assert_eq!(&target[..4], &[0x00, 0x00, 0xff, 0xff]);
+        assert!(target[4..].iter().all(|b| *b == 0));
+
+        // 负数和溢出的编码是无效的
+        assert!(compact_to_target(0x04923456).is_none());
+        assert!(compact_to_target(0xff123456).is_none());
+    }
+
+    #[test]
+    fn test_hash_meets_target() {
+        let easy = format!("0000fffe{}", "f".repeat(56));
+        let hard = format!("0001{}", "0".repeat(60));
+        assert!(hash_meets_target(&easy, 0x1f00ffff).unwrap());
+        assert!(!hash_meets_target(&hard, 0x1f00ffff).unwrap());
+    }
+
+    #[test]
+    fn test_block_work() {
+        assert_eq!(block_work(0x207fffff), 2);
+        assert_eq!(block_work(0x1f00ffff), (1u128 << 32) / 0xffff);
+        assert!(block_work(0x1e00ffff) > block_work(0x1f00ffff));
+        assert_eq!(block_work(0x04923456), 0);
+    }
+
+    #[test]
+    fn test_retarget_arithmetic() {
+        let target = compact_to_target(0x1f00ffff).unwrap();
+        let doubled = scale_target(&target, 2000, 1000).unwrap();
+        assert_eq!(target_to_compact(&doubled), 0x1f01fffe);
+        let halved = scale_target(&target, 500, 1000).unwrap();
+        assert_eq!(target_to_compact(&halved), 0x1e7fff80);
+
+        // 接近 2^256 的目标值在中间计算时不能溢出
+        let easy = compact_to_target(0x207fffff).unwrap();
+        assert_eq!(target_to_compact(&scale_target(&easy, 50_000, 200_000).unwrap()), 0x201fffff);
+        assert!(scale_target(&easy, 4, 1).is_none());
+    }
+}
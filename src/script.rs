@@ -0,0 +1,554 @@
+use std::fmt;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use serde::{Deserialize, Serialize};
+
+use crate::wallet::hash160;
+
+// 单个脚本中最多允许的非数据操作数，以及执行过程中栈的最大深度
+const MAX_OPS_PER_SCRIPT: usize = 201;
+const MAX_STACK_SIZE: usize = 1000;
+
+// checkmultisig 最多允许的公钥数量
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+// 数据输出最多携带的字节数
+pub const MAX_DATA_CARRIER_SIZE: usize = 80;
+
+// 脚本操作，数据和数字以 Push/Num 压栈，其余操作从栈上取参数
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Push(Vec<u8>),
+    Num(u64),
+    Dup,
+    Drop,
+    Hash160,
+    Sha256,
+    Equal,
+    EqualVerify,
+    Verify,
+    CheckSig,
+    CheckSigVerify,
+    CheckMultiSig,
+    CheckLockTimeVerify,
+    If,
+    Else,
+    EndIf,
+    Return,
+}
+
+// 哈希时间锁合约的哈希、接收方公钥哈希、发送方公钥哈希和超时时间
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtlcParams<'a> {
+    pub hash: &'a [u8],
+    pub recipient: &'a [u8],
+    pub sender: &'a [u8],
+    pub timeout: u64,
+}
+
+// 锁定脚本（输出）或解锁脚本（输入），执行时先运行解锁脚本，再在同一个栈上运行锁定脚本
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script(pub Vec<Op>);
+
+impl Script {
+    pub fn new() -> Script {
+        Script::default()
+    }
+
+    // 标准的支付到公钥哈希脚本，解锁脚本提供签名和公钥
+    pub fn pay_to_pubkey_hash(pub_key_hash: &[u8]) -> Script {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    // 是支付到公钥哈希脚本时返回其中的公钥哈希
+    pub fn pubkey_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::Push(hash), Op::EqualVerify, Op::CheckSig] => Some(hash),
+            _ => None,
+        }
+    }
+
+    // 支付到脚本哈希：解锁脚本最后压入的赎回脚本的哈希必须与之相等，赎回脚本随后在剩余的栈上执行
+    pub fn pay_to_script_hash(script_hash: &[u8]) -> Script {
+        Script(vec![Op::Hash160, Op::Push(script_hash.to_vec()), Op::Equal])
+    }
+
+    // 是支付到脚本哈希脚本时返回其中的脚本哈希
+    pub fn script_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Hash160, Op::Push(hash), Op::Equal] => Some(hash),
+            _ => None,
+        }
+    }
+
+    // m-of-n 多重签名脚本，需要按公钥顺序提供其中 m 个公钥的签名
+    pub fn multisig(m: usize, pub_keys: &[Vec<u8>]) -> Script {
+        let mut ops = vec![Op::Num(m as u64)];
+        ops.extend(pub_keys.iter().map(|key| Op::Push(key.clone())));
+        ops.push(Op::Num(pub_keys.len() as u64));
+        ops.push(Op::CheckMultiSig);
+        Script(ops)
+    }
+
+    // 是多重签名脚本时返回 m 和公钥列表
+    pub fn multisig_params(&self) -> Option<(usize, Vec<&[u8]>)> {
+        let (last, rest) = self.0.split_last()?;
+        let (n, rest) = rest.split_last()?;
+        let (m, keys) = rest.split_first()?;
+        let (Op::CheckMultiSig, Op::Num(n), Op::Num(m)) = (last, n, m) else {
+            return None;
+        };
+        let keys: Vec<&[u8]> = keys
+            .iter()
+            .map(|op| match op {
+                Op::Push(key) => Some(key.as_slice()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        if keys.len() as u64 != *n || m > n {
+            return None;
+        }
+        Some((*m as usize, keys))
+    }
+
+    // 哈希时间锁合约：接收方提供哈希原像和签名即可花费，超过 timeout 之后发送方可以用签名取回。
+    // 解锁脚本为 [签名, 公钥, 原像, 1] 或 [签名, 公钥, 0]
+    pub fn htlc(hash: &[u8], recipient_pkh: &[u8], sender_pkh: &[u8], timeout: u64) -> Script {
+        Script(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(hash.to_vec()),
+            Op::EqualVerify,
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(recipient_pkh.to_vec()),
+            Op::Else,
+            Op::Num(timeout),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(sender_pkh.to_vec()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    // 是哈希时间锁合约脚本时返回其中的参数
+    pub fn htlc_params(&self) -> Option<HtlcParams<'_>> {
+        match self.0.as_slice() {
+            [Op::If, Op::Sha256, Op::Push(hash), Op::EqualVerify, Op::Dup, Op::Hash160, Op::Push(recipient), Op::Else, Op::Num(timeout), Op::CheckLockTimeVerify, Op::Drop, Op::Dup, Op::Hash160, Op::Push(sender), Op::EndIf, Op::EqualVerify, Op::CheckSig] => {
+                Some(HtlcParams { hash, recipient, sender, timeout: *timeout })
+            }
+            _ => None,
+        }
+    }
+
+    // 携带任意数据的输出，Return 使脚本必然执行失败，因此输出无法花费
+    pub fn data_carrier(data: &[u8]) -> Script {
+        Script(vec![Op::Return, Op::Push(data.to_vec())])
+    }
+
+    // 是数据输出脚本时返回其中的数据
+    pub fn data(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Return, Op::Push(data)] => Some(data),
+            _ => None,
+        }
+    }
+
+    // 以 Return 开头的脚本永远无法满足，这样的输出不会进入 UTXO 集
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&Op::Return)
+    }
+
+    // 作为赎回脚本压栈时的字节形式
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap_or_default()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Script> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    // 赎回脚本对应的支付到脚本哈希的锁定脚本
+    pub fn to_p2sh(&self) -> Script {
+        Script::pay_to_script_hash(&hash160(&self.to_bytes()))
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|op| matches!(op, Op::Push(_) | Op::Num(_)))
+    }
+}
+
+// 脚本执行失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    StackUnderflow,
+    StackOverflow,
+    TooManyOps,
+    NotPushOnly,
+    BadNumber,
+    VerifyFailed,
+    EqualVerifyFailed,
+    CheckSigVerifyFailed,
+    BadPubKeyCount,
+    BadSigCount,
+    UnsatisfiedLockTime,
+    BadRedeemScript,
+    UnbalancedConditional,
+    OpReturn,
+    EvalFalse,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::StackUnderflow => write!(f, "operation needs more stack items"),
+            ScriptError::StackOverflow => write!(f, "stack is deeper than {}", MAX_STACK_SIZE),
+            ScriptError::TooManyOps => write!(f, "script has more than {} operations", MAX_OPS_PER_SCRIPT),
+            ScriptError::NotPushOnly => write!(f, "unlocking script may only push data"),
+            ScriptError::BadNumber => write!(f, "stack item is not a valid number"),
+            ScriptError::VerifyFailed => write!(f, "verify failed"),
+            ScriptError::EqualVerifyFailed => write!(f, "equalverify failed"),
+            ScriptError::CheckSigVerifyFailed => write!(f, "checksigverify failed"),
+            ScriptError::BadPubKeyCount => write!(f, "invalid public key count for checkmultisig"),
+            ScriptError::BadSigCount => write!(f, "invalid signature count for checkmultisig"),
+            ScriptError::UnsatisfiedLockTime => write!(f, "lock time has not been reached"),
+            ScriptError::BadRedeemScript => write!(f, "redeem script cannot be decoded"),
+            ScriptError::UnbalancedConditional => write!(f, "if, else and endif do not match"),
+            ScriptError::OpReturn => write!(f, "script returned early, the output is unspendable"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+// 脚本执行时依赖的交易上下文
+pub trait SignatureChecker {
+    // 签名是否由该公钥对正在花费的输入签署
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8]) -> bool;
+    // 交易是否已经到达给定的锁定时间
+    fn check_lock_time(&self, lock_time: u64) -> bool;
+}
+
+// 数字以小端字节序压栈并去掉末尾的零字节，0 是空数组
+pub fn encode_num(n: u64) -> Vec<u8> {
+    let mut bytes = n.to_le_bytes().to_vec();
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    bytes
+}
+
+fn decode_num(bytes: &[u8]) -> Result<u64, ScriptError> {
+    if bytes.len() > 8 {
+        return Err(ScriptError::BadNumber);
+    }
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(buf))
+}
+
+// 任意非零字节都视为真
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    bytes.iter().any(|b| *b != 0)
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+fn eval(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    let mut op_count = 0;
+    // 每层 If 的分支是否执行，任意一层不执行时跳过除条件控制以外的操作
+    let mut exec: Vec<bool> = Vec::new();
+    for op in &script.0 {
+        if !matches!(op, Op::Push(_) | Op::Num(_)) {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::TooManyOps);
+            }
+        }
+
+        let executing = exec.iter().all(|branch| *branch);
+        if !executing && !matches!(op, Op::If | Op::Else | Op::EndIf) {
+            continue;
+        }
+        match op {
+            Op::Push(data) => stack.push(data.clone()),
+            Op::Num(n) => stack.push(encode_num(*n)),
+            Op::Dup => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                stack.push(top);
+            }
+            Op::Drop => {
+                pop(stack)?;
+            }
+            Op::Hash160 => {
+                let data = pop(stack)?;
+                stack.push(hash160(&data));
+            }
+            Op::Sha256 => {
+                let data = pop(stack)?;
+                let mut hasher = Sha256::new();
+                hasher.input(&data);
+                let mut hash = vec![0u8; 32];
+                hasher.result(&mut hash);
+                stack.push(hash);
+            }
+            Op::Equal | Op::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                if *op == Op::Equal {
+                    stack.push(encode_bool(a == b));
+                } else if a != b {
+                    return Err(ScriptError::EqualVerifyFailed);
+                }
+            }
+            Op::Verify => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let ok = checker.check_sig(&signature, &pub_key);
+                if *op == Op::CheckSig {
+                    stack.push(encode_bool(ok));
+                } else if !ok {
+                    return Err(ScriptError::CheckSigVerifyFailed);
+                }
+            }
+            // 栈上依次为：m 个签名、m、n 个公钥、n，签名的顺序必须与公钥一致
+            Op::CheckMultiSig => {
+                let n = decode_num(&pop(stack)?)? as usize;
+                if n > MAX_PUBKEYS_PER_MULTISIG {
+                    return Err(ScriptError::BadPubKeyCount);
+                }
+                let mut pub_keys = Vec::with_capacity(n);
+                for _ in 0..n {
+                    pub_keys.push(pop(stack)?);
+                }
+                pub_keys.reverse();
+                let m = decode_num(&pop(stack)?)? as usize;
+                if m > n {
+                    return Err(ScriptError::BadSigCount);
+                }
+                let mut signatures = Vec::with_capacity(m);
+                for _ in 0..m {
+                    signatures.push(pop(stack)?);
+                }
+                signatures.reverse();
+
+                let mut keys = pub_keys.iter();
+                let ok = signatures
+                    .iter()
+                    .all(|signature| keys.any(|pub_key| checker.check_sig(signature, pub_key)));
+                stack.push(encode_bool(ok));
+            }
+            // 栈顶的锁定时间保留在栈上，通常后面跟 Drop
+            Op::CheckLockTimeVerify => {
+                let lock_time = decode_num(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                if !checker.check_lock_time(lock_time) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            }
+            Op::If => {
+                let branch = executing && cast_to_bool(&pop(stack)?);
+                exec.push(branch);
+            }
+            Op::Else => {
+                let branch = exec.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                *branch = !*branch;
+            }
+            Op::EndIf => {
+                exec.pop().ok_or(ScriptError::UnbalancedConditional)?;
+            }
+            Op::Return => return Err(ScriptError::OpReturn),
+        }
+
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+    }
+    if !exec.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+fn check_top(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+// 用见证中的解锁脚本满足锁定脚本，执行结束后栈顶必须为真。
+// 锁定脚本是支付到脚本哈希时，还要在解锁脚本留下的栈上执行其中最后压入的赎回脚本
+pub fn verify_script(witness: &Script, script_pubkey: &Script, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if !witness.is_push_only() {
+        return Err(ScriptError::NotPushOnly);
+    }
+    let mut stack = Vec::new();
+    eval(witness, &mut stack, checker)?;
+    let mut redeem_stack = script_pubkey.script_hash().map(|_| stack.clone());
+    eval(script_pubkey, &mut stack, checker)?;
+    check_top(&stack)?;
+
+    if let Some(stack) = &mut redeem_stack {
+        let redeem_script = Script::from_bytes(&pop(stack)?).ok_or(ScriptError::BadRedeemScript)?;
+        eval(&redeem_script, stack, checker)?;
+        check_top(stack)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 签名等于公钥加上 "sig" 时视为有效，锁定时间不超过 10 时视为到达
+    struct FakeChecker;
+
+    impl SignatureChecker for FakeChecker {
+        fn check_sig(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+            signature == [pub_key, b"sig"].concat()
+        }
+
+        fn check_lock_time(&self, lock_time: u64) -> bool {
+            lock_time <= 10
+        }
+    }
+
+    fn sig(pub_key: &[u8]) -> Op {
+        Op::Push([pub_key, b"sig"].concat())
+    }
+
+    #[test]
+    fn test_pay_to_pubkey_hash() {
+        let hash = hash160(b"alice");
+        let lock = Script::pay_to_pubkey_hash(&hash);
+        assert_eq!(lock.pubkey_hash(), Some(hash.as_slice()));
+
+        let unlock = Script(vec![sig(b"alice"), Op::Push(b"alice".to_vec())]);
+        assert_eq!(verify_script(&unlock, &lock, &FakeChecker), Ok(()));
+
+        let wrong_key = Script(vec![sig(b"bob"), Op::Push(b"bob".to_vec())]);
+        assert_eq!(verify_script(&wrong_key, &lock, &FakeChecker), Err(ScriptError::EqualVerifyFailed));
+
+        let bad_sig = Script(vec![sig(b"bob"), Op::Push(b"alice".to_vec())]);
+        assert_eq!(verify_script(&bad_sig, &lock, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        let not_push = Script(vec![Op::Num(1), Op::Dup]);
+        assert_eq!(verify_script(&not_push, &lock, &FakeChecker), Err(ScriptError::NotPushOnly));
+        assert_eq!(verify_script(&Script::new(), &lock, &FakeChecker), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_checkmultisig() {
+        let keys: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+        let mut lock = vec![Op::Num(2)];
+        lock.extend(keys.iter().map(|k| Op::Push(k.to_vec())));
+        lock.extend([Op::Num(3), Op::CheckMultiSig]);
+        let lock = Script(lock);
+
+        let ok = Script(vec![sig(b"a"), sig(b"c")]);
+        assert_eq!(verify_script(&ok, &lock, &FakeChecker), Ok(()));
+
+        // 签名顺序与公钥顺序不一致
+        let unordered = Script(vec![sig(b"c"), sig(b"a")]);
+        assert_eq!(verify_script(&unordered, &lock, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        // 同一个公钥的签名不能用两次
+        let reused = Script(vec![sig(b"a"), sig(b"a")]);
+        assert_eq!(verify_script(&reused, &lock, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        let too_few = Script(vec![sig(b"a")]);
+        assert_eq!(verify_script(&too_few, &lock, &FakeChecker), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_pay_to_script_hash() {
+        let keys: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let redeem = Script::multisig(2, &keys);
+        assert_eq!(redeem.multisig_params(), Some((2, vec![&b"a"[..], b"b", b"c"])));
+        let lock = redeem.to_p2sh();
+        assert!(lock.script_hash().is_some());
+
+        let redeem_push = Op::Push(redeem.to_bytes());
+        let ok = Script(vec![sig(b"b"), sig(b"c"), redeem_push.clone()]);
+        assert_eq!(verify_script(&ok, &lock, &FakeChecker), Ok(()));
+
+        // 只有一个签名时赎回脚本执行失败
+        let partial = Script(vec![sig(b"b"), redeem_push]);
+        assert_eq!(verify_script(&partial, &lock, &FakeChecker), Err(ScriptError::StackUnderflow));
+
+        // 换成只需要一个签名的赎回脚本会因为哈希不同而失败
+        let other = Script::multisig(1, &keys);
+        let swapped = Script(vec![sig(b"b"), Op::Push(other.to_bytes())]);
+        assert_eq!(verify_script(&swapped, &lock, &FakeChecker), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn test_htlc() {
+        let mut hasher = Sha256::new();
+        hasher.input(b"secret");
+        let mut hash = vec![0u8; 32];
+        hasher.result(&mut hash);
+        let lock = |timeout| Script::htlc(&hash, &hash160(b"bob"), &hash160(b"alice"), timeout);
+        assert_eq!(lock(10).htlc_params().map(|params| params.timeout), Some(10));
+
+        // 接收方凭原像领取，原像错误时失败
+        let claim = |secret: &[u8]| Script(vec![sig(b"bob"), Op::Push(b"bob".to_vec()), Op::Push(secret.to_vec()), Op::Num(1)]);
+        assert_eq!(verify_script(&claim(b"secret"), &lock(10), &FakeChecker), Ok(()));
+        assert_eq!(verify_script(&claim(b"guess"), &lock(10), &FakeChecker), Err(ScriptError::EqualVerifyFailed));
+
+        // 发送方在超时之后取回，接收方不能走这个分支
+        let refund = |who: &[u8]| Script(vec![sig(who), Op::Push(who.to_vec()), Op::Num(0)]);
+        assert_eq!(verify_script(&refund(b"alice"), &lock(10), &FakeChecker), Ok(()));
+        assert_eq!(verify_script(&refund(b"alice"), &lock(11), &FakeChecker), Err(ScriptError::UnsatisfiedLockTime));
+        assert_eq!(verify_script(&refund(b"bob"), &lock(10), &FakeChecker), Err(ScriptError::EqualVerifyFailed));
+
+        let unbalanced = Script(vec![Op::Num(1), Op::If, Op::Num(1)]);
+        assert_eq!(verify_script(&Script::new(), &unbalanced, &FakeChecker), Err(ScriptError::UnbalancedConditional));
+        let stray_else = Script(vec![Op::Num(1), Op::Else]);
+        assert_eq!(verify_script(&Script::new(), &stray_else, &FakeChecker), Err(ScriptError::UnbalancedConditional));
+    }
+
+    #[test]
+    fn test_data_carrier() {
+        let lock = Script::data_carrier(b"document hash");
+        assert_eq!(lock.data(), Some(&b"document hash"[..]));
+        assert!(lock.is_unspendable());
+        assert!(!Script::pay_to_pubkey_hash(&hash160(b"alice")).is_unspendable());
+        assert_eq!(verify_script(&Script(vec![Op::Num(1)]), &lock, &FakeChecker), Err(ScriptError::OpReturn));
+    }
+
+    #[test]
+    fn test_checklocktimeverify() {
+        let lock = |height| Script(vec![Op::Num(height), Op::CheckLockTimeVerify, Op::Drop, Op::Num(1)]);
+        assert_eq!(verify_script(&Script::new(), &lock(10), &FakeChecker), Ok(()));
+        assert_eq!(verify_script(&Script::new(), &lock(11), &FakeChecker), Err(ScriptError::UnsatisfiedLockTime));
+        assert_eq!(encode_num(0), Vec::<u8>::new());
+        assert_eq!(decode_num(&encode_num(500_000)), Ok(500_000));
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crypto::{digest::Digest, sha2::Sha256};
+
+// 默认最多缓存的签名数量
+pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+// 已经验证通过的签名，以签名哈希、公钥和签名三者的哈希为键。
+// 交易进入交易池时验证过的签名在区块连接时可以直接跳过，缓存满了之后随意淘汰一条旧记录。
+// 缓存只保存在内存中，只在同一个进程内有效
+#[derive(Debug)]
+pub struct SignatureCache {
+    entries: Mutex<HashSet<[u8; 32]>>,
+    max_entries: usize,
+    hits: AtomicUsize,
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        SignatureCache::new(DEFAULT_MAX_ENTRIES)
+    }
+}
+
+impl SignatureCache {
+    pub fn new(max_entries: usize) -> SignatureCache {
+        SignatureCache {
+            entries: Mutex::new(HashSet::new()),
+            max_entries,
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    fn key(message: &[u8], pub_key: &[u8], signature: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in [message, pub_key, signature] {
+            hasher.input(&(part.len() as u64).to_be_bytes());
+            hasher.input(part);
+        }
+        let mut key = [0u8; 32];
+        hasher.result(&mut key);
+        key
+    }
+
+    pub fn contains(&self, message: &[u8], pub_key: &[u8], signature: &[u8]) -> bool {
+        let key = SignatureCache::key(message, pub_key, signature);
+        let found = self.entries.lock().map(|entries| entries.contains(&key)).unwrap_or(false);
+        if found {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    // 命中缓存、跳过验证的签名次数
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    // 只应该插入验证通过的签名
+    pub fn insert(&self, message: &[u8], pub_key: &[u8], signature: &[u8]) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let key = SignatureCache::key(message, pub_key, signature);
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() >= self.max_entries && !entries.contains(&key) {
+                if let Some(evicted) = entries.iter().next().copied() {
+                    entries.remove(&evicted);
+                }
+            }
+            entries.insert(key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|entries| entries.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded_cache() {
+        let cache = SignatureCache::new(2);
+        cache.insert(b"msg", b"key", b"sig");
+        assert!(cache.contains(b"msg", b"key", b"sig"));
+        // 三部分的边界不同也是不同的记录
+        assert!(!cache.contains(b"ms", b"gkey", b"sig"));
+
+        cache.insert(b"msg", b"key", b"sig");
+        cache.insert(b"msg2", b"key", b"sig");
+        cache.insert(b"msg3", b"key", b"sig");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(b"msg3", b"key", b"sig"));
+        assert_eq!(cache.hits(), 2);
+        assert!(SignatureCache::new(0).is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::File;
+use std::path::Path;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+use sled::transaction::TransactionError;
+use sled::Transactional;
+
+use crate::block::Block;
+use crate::errors::{ChainError, Result};
+use crate::mempool::MempoolEntry;
+use crate::tx::{BlockUndo, TXOutputs};
+
+const TIP_KEY: &str = "LAST";
+
+type ColumnData = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// 存储中的逻辑分区，sled 后端中每个分区对应一棵独立的 Tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    Blocks,
+    Meta,
+    Utxos,
+    Heights,
+    ChainWork,
+    Undo,
+    Mempool,
+    Children,
+}
+
+impl Column {
+    pub const ALL: [Column; 8] = [
+        Column::Blocks,
+        Column::Meta,
+        Column::Utxos,
+        Column::Heights,
+        Column::ChainWork,
+        Column::Undo,
+        Column::Mempool,
+        Column::Children,
+    ];
+
+    // 创建新链时重建的分区，交易池和撤销记录不在其中
+    pub const CHAIN: [Column; 6] =
+        [Column::Blocks, Column::Meta, Column::Utxos, Column::Heights, Column::ChainWork, Column::Children];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Column::Blocks => "blocks",
+            Column::Meta => "meta",
+            Column::Utxos => "utxos",
+            Column::Heights => "heights",
+            Column::ChainWork => "chainwork",
+            Column::Undo => "undo",
+            Column::Mempool => "mempool",
+            Column::Children => "children",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Column::Blocks => 0,
+            Column::Meta => 1,
+            Column::Utxos => 2,
+            Column::Heights => 3,
+            Column::ChainWork => 4,
+            Column::Undo => 5,
+            Column::Mempool => 6,
+            Column::Children => 7,
+        }
+    }
+}
+
+// 高度使用大端序编码，保证按键排序时与高度顺序一致
+fn height_key(height: usize) -> [u8; 8] {
+    (height as u64).to_be_bytes()
+}
+
+#[derive(Debug, Clone)]
+enum BatchOp {
+    Put(Column, Vec<u8>, Vec<u8>),
+    Delete(Column, Vec<u8>),
+}
+
+// 一组需要原子写入的操作，要么全部生效，要么全部不生效
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn put(&mut self, column: Column, key: &[u8], value: Vec<u8>) {
+        self.ops.push(BatchOp::Put(column, key.to_vec(), value));
+    }
+
+    pub fn delete(&mut self, column: Column, key: &[u8]) {
+        self.ops.push(BatchOp::Delete(column, key.to_vec()));
+    }
+
+    pub fn put_block(&mut self, block: &Block) -> Result<()> {
+        self.put(Column::Blocks, block.get_hash().as_bytes(), bincode::serialize(block)?);
+        Ok(())
+    }
+
+    pub fn set_tip(&mut self, hash: &str) {
+        self.put(Column::Meta, TIP_KEY.as_bytes(), hash.as_bytes().to_vec());
+    }
+
+    // 主链上的高度索引：高度 -> 区块哈希
+    pub fn put_height(&mut self, height: usize, hash: &str) {
+        self.put(Column::Heights, &height_key(height), hash.as_bytes().to_vec());
+    }
+
+    // 父区块哈希 -> 已保存的子区块哈希，用于从某个区块向后找到它的所有后代
+    pub fn put_children(&mut self, hash: &str, children: &[String]) -> Result<()> {
+        self.put(Column::Children, hash.as_bytes(), bincode::serialize(children)?);
+        Ok(())
+    }
+
+    // 删除区块及其累计工作量、撤销记录和子区块索引，用于丢弃无效区块
+    pub fn delete_block(&mut self, hash: &str) {
+        self.delete(Column::Blocks, hash.as_bytes());
+        self.delete(Column::Children, hash.as_bytes());
+        self.delete(Column::ChainWork, hash.as_bytes());
+        self.delete(Column::Undo, hash.as_bytes());
+    }
+
+    pub fn put_undo(&mut self, hash: &str, undo: &BlockUndo) -> Result<()> {
+        self.put(Column::Undo, hash.as_bytes(), bincode::serialize(undo)?);
+        Ok(())
+    }
+
+    pub fn delete_height(&mut self, height: usize) {
+        self.delete(Column::Heights, &height_key(height));
+    }
+
+    // 从创世区块到该区块的累计工作量
+    pub fn put_chain_work(&mut self, hash: &str, work: u128) {
+        self.put(Column::ChainWork, hash.as_bytes(), work.to_be_bytes().to_vec());
+    }
+
+    // 交易池中尚未确认的交易，按交易 id 保存
+    pub fn put_mempool_entry(&mut self, entry: &MempoolEntry) -> Result<()> {
+        self.put(Column::Mempool, entry.tx.id.as_bytes(), bincode::serialize(entry)?);
+        Ok(())
+    }
+
+    pub fn delete_mempool_tx(&mut self, txid: &str) {
+        self.delete(Column::Mempool, txid.as_bytes());
+    }
+
+    pub fn put_utxos(&mut self, txid: &str, outs: &TXOutputs) -> Result<()> {
+        self.put(Column::Utxos, txid.as_bytes(), bincode::serialize(outs)?);
+        Ok(())
+    }
+
+    pub fn delete_utxos(&mut self, txid: &str) {
+        self.delete(Column::Utxos, txid.as_bytes());
+    }
+}
+
+// 区块链存储后端。实现者只需提供按分区的原始读写，类型化的读取方法由默认实现提供
+pub trait ChainStore: Debug + Send + Sync {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn scan(&self, column: Column) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    fn clear(&self, column: Column) -> Result<()>;
+
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        match self.get(Column::Blocks, hash.as_bytes())? {
+            Some(b) => Ok(Some(bincode::deserialize(&b)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        match self.get(Column::Meta, TIP_KEY.as_bytes())? {
+            Some(h) => Ok(Some(String::from_utf8(h)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<Option<String>> {
+        match self.get(Column::Heights, &height_key(height))? {
+            Some(h) => Ok(Some(String::from_utf8(h)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_chain_work(&self, hash: &str) -> Result<Option<u128>> {
+        match self.get(Column::ChainWork, hash.as_bytes())? {
+            Some(w) => {
+                let bytes: [u8; 16] = w
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| ChainError::Storage(format!("corrupt chain work for {}", hash)))?;
+                Ok(Some(u128::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_children(&self, hash: &str) -> Result<Vec<String>> {
+        match self.get(Column::Children, hash.as_bytes())? {
+            Some(children) => Ok(bincode::deserialize(&children)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn get_undo(&self, hash: &str) -> Result<Option<BlockUndo>> {
+        match self.get(Column::Undo, hash.as_bytes())? {
+            Some(undo) => Ok(Some(bincode::deserialize(&undo)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        match self.get(Column::Utxos, txid.as_bytes())? {
+            Some(outs) => Ok(Some(bincode::deserialize(&outs)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn mempool_entries(&self) -> Result<Vec<MempoolEntry>> {
+        let mut entries = Vec::new();
+        for (_, v) in self.scan(Column::Mempool)? {
+            entries.push(bincode::deserialize(&v)?);
+        }
+        Ok(entries)
+    }
+
+    fn utxos(&self) -> Result<Vec<(String, TXOutputs)>> {
+        let mut utxos = Vec::new();
+        for (k, v) in self.scan(Column::Utxos)? {
+            utxos.push((String::from_utf8(k)?, bincode::deserialize(&v)?));
+        }
+        Ok(utxos)
+    }
+}
+
+// 等待文件锁释放的最多次数和间隔
+const LOCK_RETRIES: usize = 50;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub struct SledStore {
+    db: sled::Db,
+    trees: Vec<sled::Tree>,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStore> {
+        SledStore::wait_for_lock(path.as_ref());
+        let db = sled::open(path)?;
+        let mut trees = Vec::new();
+        for column in Column::ALL {
+            trees.push(db.open_tree(column.name())?);
+        }
+        Ok(SledStore { db, trees })
+    }
+
+    // 同一进程刚关闭的数据库，sled 的后台线程可能还短暂持有文件锁。
+    // 打开之前有限次地等待锁释放，仍被占用时交给 sled::open 报告错误
+    fn wait_for_lock(path: &Path) {
+        let Ok(file) = File::open(path.join("db")) else {
+            return;
+        };
+        for _ in 0..LOCK_RETRIES {
+            if file.try_lock_exclusive().is_ok() {
+                let _ = FileExt::unlock(&file);
+                return;
+            }
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+    }
+
+    fn tree(&self, column: Column) -> &sled::Tree {
+        &self.trees[column.index()]
+    }
+}
+
+impl ChainStore for SledStore {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree(column).get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn scan(&self, column: Column) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut items = Vec::new();
+        for kv in self.tree(column).iter() {
+            let (k, v) = kv?;
+            items.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(items)
+    }
+
+    fn clear(&self, column: Column) -> Result<()> {
+        self.tree(column).clear()?;
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let trees: Vec<&sled::Tree> = self.trees.iter().collect();
+        let res: std::result::Result<(), TransactionError<()>> =
+            trees.as_slice().transaction(|views| {
+                for op in &batch.ops {
+                    match op {
+                        BatchOp::Put(column, k, v) => {
+                            views[column.index()].insert(k.as_slice(), v.as_slice())?;
+                        }
+                        BatchOp::Delete(column, k) => {
+                            views[column.index()].remove(k.as_slice())?;
+                        }
+                    }
+                }
+                Ok(())
+            });
+        match res {
+            Ok(()) => {}
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+            Err(TransactionError::Abort(())) => return Err(ChainError::Storage(String::from("write batch aborted"))),
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// 纯内存后端，用于测试和模拟，不会访问磁盘
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    columns: RwLock<HashMap<Column, ColumnData>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let columns = self.columns.read().map_err(|_| ChainError::Storage(String::from("memory store poisoned")))?;
+        Ok(columns.get(&column).and_then(|c| c.get(key)).cloned())
+    }
+
+    fn scan(&self, column: Column) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let columns = self.columns.read().map_err(|_| ChainError::Storage(String::from("memory store poisoned")))?;
+        Ok(columns
+            .get(&column)
+            .map(|c| c.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    fn clear(&self, column: Column) -> Result<()> {
+        let mut columns = self.columns.write().map_err(|_| ChainError::Storage(String::from("memory store poisoned")))?;
+        columns.remove(&column);
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut columns = self.columns.write().map_err(|_| ChainError::Storage(String::from("memory store poisoned")))?;
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(column, k, v) => {
+                    columns.entry(column).or_default().insert(k, v);
+                }
+                BatchOp::Delete(column, k) => {
+                    if let Some(c) = columns.get_mut(&column) {
+                        c.remove(&k);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::temp_config;
+
+    fn check_batch(store: &dyn ChainStore) {
+        let mut batch = WriteBatch::new();
+        batch.set_tip("abc");
+        batch.put(Column::Utxos, b"tx1", vec![1]);
+        batch.put(Column::Utxos, b"tx2", vec![2]);
+        batch.delete(Column::Utxos, b"tx1");
+        store.write(batch).unwrap();
+
+        assert_eq!(store.get_tip().unwrap(), Some("abc".to_string()));
+        assert_eq!(store.get(Column::Utxos, b"tx1").unwrap(), None);
+        assert_eq!(store.scan(Column::Utxos).unwrap(), vec![(b"tx2".to_vec(), vec![2])]);
+
+        store.clear(Column::Utxos).unwrap();
+        assert!(store.scan(Column::Utxos).unwrap().is_empty());
+        assert!(store.get_tip().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_batch(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sled_store() {
+        let config = temp_config("sled_store");
+        check_batch(&SledStore::open(config.data_dir.chainstate()).unwrap());
+        std::fs::remove_dir_all(config.data_dir.root()).unwrap();
+    }
+}
@@ -1,145 +1,532 @@
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crypto::ed25519;
 use crypto::{digest::Digest, sha2::Sha256};
-use failure::format_err;
 use serde::{Serialize,Deserialize};
-use log::error;
+use log::{debug, error};
+use crate::amount::Amount;
+use crate::mempool::Mempool;
+use crate::script::{verify_script, Op, Script, SignatureChecker, MAX_DATA_CARRIER_SIZE};
+use crate::sigcache::SignatureCache;
 use crate::utxoset::UTXOSet;
-use crate::wallet::{ hash_pub_key, Wallets};
-use crate:: errors::Result;
-use crate::tx::{TXInput, TXOutput};
+use crate::wallet::{hash160, hash_pub_key, script_for_address, Wallets};
+use crate::errors::{ChainError, Result};
+use crate::tx::{TXInput, TXOutput, SEQUENCE_FINAL};
+
+// 初始的挖矿奖励，之后按高度减半，见 validation::block_subsidy
+pub const SUBSIDY: Amount = Amount::from_coins(100);
+
+// coinbase 输入数据的前缀：区块高度和额外随机数各 8 字节
+const COINBASE_PREFIX_LEN: usize = 16;
+
+// coinbase 输入中依次写入高度、额外随机数和任意数据，保证不同区块的 coinbase 交易 id 不同
+fn coinbase_script(height: usize, extra_nonce: u64, data: &[u8]) -> Vec<u8> {
+    let mut script = Vec::with_capacity(COINBASE_PREFIX_LEN + data.len());
+    script.extend_from_slice(&(height as u64).to_be_bytes());
+    script.extend_from_slice(&extra_nonce.to_be_bytes());
+    script.extend_from_slice(data);
+    script
+}
+
+// 按每 1000 字节的费率计算手续费，向上取整
+pub fn fee_for_size(size: usize, feerate: Amount) -> Amount {
+    let fee = (size as u128 * feerate.as_units() as u128).div_ceil(1000);
+    Amount::from_units(fee.min(u64::MAX as u128) as u64)
+}
+
+// ed25519 私钥的后 32 字节就是公钥
+fn public_key(private_key: &[u8]) -> Result<&[u8]> {
+    private_key
+        .get(32..64)
+        .ok_or_else(|| ChainError::Wallet(String::from("invalid private key")))
+}
+
+// 在签名时提供的前序交易中找到输入花费的输出
+fn prev_output<'a>(prev_txs: &'a HashMap<String, Transaction>, vin: &TXInput) -> Result<&'a TXOutput> {
+    prev_txs
+        .get(&vin.txid)
+        .and_then(|prev_tx| prev_tx.vout.get(vin.vout as usize))
+        .ok_or_else(|| ChainError::NotFound(format!("previous output {}:{}", vin.txid, vin.vout)))
+}
+
+fn sha256_hex<T: Serialize>(value: &T) -> Result<String> {
+    let data = bincode::serialize(value)?;
+    let mut hasher = Sha256::new();
+    hasher.input(&data[..]);
+    Ok(hasher.result_str())
+}
+
+// 签名哈希类型，附加在签名的最后一个字节，决定签名覆盖交易的哪些部分
+pub const SIGHASH_ALL: u8 = 0x01; // 所有输入和输出
+pub const SIGHASH_NONE: u8 = 0x02; // 所有输入，不包括输出，其他输入的 sequence 也不覆盖
+pub const SIGHASH_SINGLE: u8 = 0x03; // 所有输入和与当前输入位置相同的输出
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80; // 与以上类型组合使用，只覆盖当前输入，其他人可以继续添加输入
+pub const SIGHASH_V1: u8 = 0x40; // 与以上类型组合使用，选择覆盖被花费输出金额和锁定脚本的版本 1 签名哈希
+
+// ed25519 签名的长度，解锁脚本中的签名还要再加上一个字节的签名哈希类型
+const SIGNATURE_LEN: usize = 64;
+
+fn is_valid_hash_type(hash_type: u8) -> bool {
+    matches!(hash_type & !(SIGHASH_ANYONECANPAY | SIGHASH_V1), SIGHASH_ALL | SIGHASH_NONE | SIGHASH_SINGLE)
+}
+
+// 版本 1 签名哈希中与具体输入无关的部分，对同一笔交易只计算一次，使所有输入的签名哈希总计算量与交易大小成线性关系
+struct SighashCache {
+    spent: Vec<TXOutput>, // 每个输入花费的输出
+    prevouts: String,
+    amounts: String,
+    script_pubkeys: String,
+    sequences: String,
+    outputs: String,
+}
+
+impl SighashCache {
+    fn new(tx: &Transaction, prev_txs: &HashMap<String, Transaction>) -> Result<SighashCache> {
+        let spent = tx
+            .vin
+            .iter()
+            .map(|vin| prev_output(prev_txs, vin).cloned())
+            .collect::<Result<Vec<TXOutput>>>()?;
+        SighashCache::from_spent(tx, spent)
+    }
+
+    // spent 是每个输入花费的输出，按输入的顺序排列
+    fn from_spent(tx: &Transaction, spent: Vec<TXOutput>) -> Result<SighashCache> {
+        if spent.len() != tx.vin.len() {
+            return Err(ChainError::InvalidInput(format!(
+                "transaction {} has {} inputs but {} spent outputs were given",
+                tx.id,
+                tx.vin.len(),
+                spent.len()
+            )));
+        }
+        let prevouts: Vec<(&str, i32)> = tx.vin.iter().map(|vin| (vin.txid.as_str(), vin.vout)).collect();
+        let amounts: Vec<Amount> = spent.iter().map(|out| out.value).collect();
+        let script_pubkeys: Vec<&Script> = spent.iter().map(|out| &out.script_pubkey).collect();
+        let sequences: Vec<u32> = tx.vin.iter().map(|vin| vin.sequence).collect();
+        Ok(SighashCache {
+            prevouts: sha256_hex(&prevouts)?,
+            amounts: sha256_hex(&amounts)?,
+            script_pubkeys: sha256_hex(&script_pubkeys)?,
+            sequences: sha256_hex(&sequences)?,
+            outputs: sha256_hex(&tx.vout)?,
+            spent,
+        })
+    }
+}
+
+// 版本 1 签名哈希的原像。不覆盖的部分为空字符串
+#[derive(Serialize)]
+struct SighashPreimage<'a> {
+    hash_type: u8,
+    lock_time: u64,
+    prevouts: &'a str,
+    amounts: &'a str,
+    script_pubkeys: &'a str,
+    sequences: &'a str,
+    outputs: &'a str,
+    txid: &'a str,
+    vout: i32,
+    value: Amount,
+    script_code: &'a Script,
+    sequence: u32,
+}
+
+// 脚本执行时对正在花费的输入进行签名和锁定时间检查
+struct TransactionChecker<'a> {
+    tx: &'a Transaction,
+    input: usize,
+    script_code: &'a Script,
+    cache: &'a SighashCache,
+    sig_cache: Option<&'a SignatureCache>,
+}
+
+impl SignatureChecker for TransactionChecker<'_> {
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+        // 长度不对的公钥或签名会让 ed25519::verify 越界
+        if pub_key.len() != 32 || signature.len() != SIGNATURE_LEN + 1 {
+            return false;
+        }
+        let (signature, hash_type) = signature.split_at(SIGNATURE_LEN);
+        let hash = match self.tx.signature_hash(self.input, self.script_code, hash_type[0], self.cache) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        // 签名哈希已经包含了签名哈希类型，缓存的键不需要再加上它
+        if self.sig_cache.is_some_and(|sig_cache| sig_cache.contains(hash.as_bytes(), pub_key, signature)) {
+            return true;
+        }
+        let valid = ed25519::verify(hash.as_bytes(), pub_key, signature);
+        if let (true, Some(sig_cache)) = (valid, self.sig_cache) {
+            sig_cache.insert(hash.as_bytes(), pub_key, signature);
+        }
+        valid
+    }
+
+    // 交易自身的锁定时间必须不早于脚本要求的锁定时间，且两者同为高度或同为时间；
+    // 输入的 sequence 为 SEQUENCE_FINAL 时交易的锁定时间不生效，因此也不满足
+    fn check_lock_time(&self, lock_time: u64) -> bool {
+        let tx_lock_time = self.tx.lock_time;
+        if (lock_time < LOCKTIME_THRESHOLD) != (tx_lock_time < LOCKTIME_THRESHOLD) || lock_time > tx_lock_time {
+            return false;
+        }
+        self.tx.vin[self.input].sequence != SEQUENCE_FINAL
+    }
+}
+
+// 一笔交易所有输入的脚本检查。签名哈希中与输入无关的部分只计算一次，
+// 各个输入之间互不依赖，验证区块时可以把所有交易的输入分散到多个线程上执行
+pub struct ScriptChecks<'a> {
+    tx: &'a Transaction,
+    cache: SighashCache,
+}
+
+impl<'a> ScriptChecks<'a> {
+    // spent 是每个输入花费的输出，按输入的顺序排列，可以直接取自 UTXO 集而不需要完整的前序交易
+    pub fn new(tx: &'a Transaction, spent: Vec<TXOutput>) -> Result<ScriptChecks<'a>> {
+        Ok(ScriptChecks {
+            tx,
+            cache: SighashCache::from_spent(tx, spent)?,
+        })
+    }
+
+    pub fn tx(&self) -> &'a Transaction {
+        self.tx
+    }
+
+    // 执行第 input 个输入的见证和被花费输出的锁定脚本，提供 sig_cache 时跳过其中已经验证过的签名，并记录新验证通过的签名
+    pub fn check(&self, input: usize, sig_cache: Option<&SignatureCache>) -> bool {
+        let prev_out = &self.cache.spent[input];
+        let checker = TransactionChecker {
+            tx: self.tx,
+            input,
+            script_code: &prev_out.script_pubkey,
+            cache: &self.cache,
+            sig_cache,
+        };
+        match verify_script(self.tx.witness(input), &prev_out.script_pubkey, &checker) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("input {} of transaction {} failed script verification: {}", input, self.tx.id, e);
+                false
+            }
+        }
+    }
+}
+
+// 锁定时间小于该值时表示区块高度，否则表示以毫秒计的时间戳
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    pub lock_time: u64, // 交易只能打包进高度（或父区块时间戳）大于该值的区块，0 表示不限制
+    pub witnesses: Vec<Script>, // 见证：与输入一一对应的解锁脚本，不计入交易 id，未签名时为空
 }
 
+// 没有见证的输入按空的解锁脚本验证
+static EMPTY_WITNESS: Script = Script(Vec::new());
+
 
 impl Transaction {
 
+    // 用私钥为每个输入生成支付到公钥哈希的解锁脚本，签名覆盖所有输入和输出。支付到脚本哈希的输入需要用 sign_multisig 签名，
+    // 哈希时间锁合约的输入需要用 sign_htlc 签名
     pub fn sign(&mut self, private_key: &[u8], prev_txs: HashMap<String, Transaction>) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: previous transaction is not correct"));
+        let cache = SighashCache::new(self, &prev_txs)?;
+        for in_id in 0..self.vin.len() {
+            let script_pubkey = &cache.spent[in_id].script_pubkey;
+            if script_pubkey.script_hash().is_some() || script_pubkey.htlc_params().is_some() {
+                continue;
             }
+            self.sign_pubkey_hash_input(in_id, private_key, SIGHASH_ALL | SIGHASH_V1, &cache)?;
+        }
+
+        Ok(())
+    }
+
+    // 用给定的签名哈希类型为第 input 个输入生成支付到公钥哈希的解锁脚本。
+    // 各方可以只为自己的输入签名，例如以 SIGHASH_ALL | SIGHASH_ANYONECANPAY 共同出资支付同一组输出；
+    // 加上 SIGHASH_V1 时签名还覆盖被花费输出的金额，离线签名也能确认手续费
+    pub fn sign_input(&mut self, input: usize, private_key: &[u8], hash_type: u8, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+        if input >= self.vin.len() {
+            return Err(ChainError::InvalidInput(format!("transaction {} has no input {}", self.id, input)));
         }
+        let cache = SighashCache::new(self, prev_txs)?;
+        self.sign_pubkey_hash_input(input, private_key, hash_type, &cache)
+    }
 
-        let mut tx_copy: Transaction = self.trim_copy();
+    fn sign_pubkey_hash_input(&mut self, input: usize, private_key: &[u8], hash_type: u8, cache: &SighashCache) -> Result<()> {
+        let pub_key = public_key(private_key)?.to_vec();
+        let signature = self.create_signature(input, &cache.spent[input].script_pubkey, private_key, hash_type, cache)?;
+        self.set_witness(input, Script(vec![Op::Push(signature), Op::Push(pub_key)]));
+        Ok(())
+    }
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+    // 为花费多重签名输出的第 input 个输入添加一个签名，可以由各个持有人依次调用。
+    // 解锁脚本中的签名按公钥在赎回脚本中的顺序排列，最后是赎回脚本本身，凑够 m 个签名后即可通过验证
+    pub fn sign_multisig(&mut self, input: usize, private_key: &[u8], redeem_script: &Script, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+        let vin = self
+            .vin
+            .get(input)
+            .ok_or_else(|| ChainError::InvalidInput(format!("transaction {} has no input {}", self.id, input)))?;
+        let prev_out = prev_output(prev_txs, vin)?;
+        if prev_out.script_pubkey != redeem_script.to_p2sh() {
+            return Err(ChainError::InvalidInput(format!("redeem script does not match output {}:{}", vin.txid, vin.vout)));
         }
+        let (m, pub_keys) = redeem_script
+            .multisig_params()
+            .ok_or_else(|| ChainError::InvalidInput(String::from("redeem script is not a multisig script")))?;
+        let pub_key = public_key(private_key)?;
+        let index = pub_keys
+            .iter()
+            .position(|key| *key == pub_key)
+            .ok_or_else(|| ChainError::Wallet(String::from("key is not part of the multisig")))?;
+
+        // 已有的签名属于哪个公钥需要逐个验证才能知道
+        let cache = SighashCache::new(self, prev_txs)?;
+        let checker = TransactionChecker {
+            tx: self,
+            input,
+            script_code: &prev_out.script_pubkey,
+            cache: &cache,
+            sig_cache: None,
+        };
+        let mut signatures = BTreeMap::new();
+        let pushed = self.witness(input).0.split_last().map(|(_, sigs)| sigs).unwrap_or_default();
+        for op in pushed {
+            if let Op::Push(signature) = op {
+                if let Some(owner) = pub_keys.iter().position(|key| checker.check_sig(signature, key)) {
+                    signatures.insert(owner, signature.clone());
+                }
+            }
+        }
+        signatures.insert(index, self.create_signature(input, &prev_out.script_pubkey, private_key, SIGHASH_ALL | SIGHASH_V1, &cache)?);
 
+        let mut ops: Vec<Op> = signatures.into_values().take(m).map(Op::Push).collect();
+        ops.push(Op::Push(redeem_script.to_bytes()));
+        self.set_witness(input, Script(ops));
+        Ok(())
+    }
+
+    // 为花费哈希时间锁合约的第 input 个输入签名，提供原像时走接收方分支，否则走超时后的发送方分支
+    pub fn sign_htlc(&mut self, input: usize, private_key: &[u8], preimage: Option<&[u8]>, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+        let vin = self
+            .vin
+            .get(input)
+            .ok_or_else(|| ChainError::InvalidInput(format!("transaction {} has no input {}", self.id, input)))?;
+        let prev_out = prev_output(prev_txs, vin)?;
+        if prev_out.script_pubkey.htlc_params().is_none() {
+            return Err(ChainError::InvalidInput(format!("output {}:{} is not a hash time-locked contract", vin.txid, vin.vout)));
+        }
+        let pub_key = public_key(private_key)?.to_vec();
+        let cache = SighashCache::new(self, prev_txs)?;
+        let signature = self.create_signature(input, &prev_out.script_pubkey, private_key, SIGHASH_ALL | SIGHASH_V1, &cache)?;
+        let mut ops = vec![Op::Push(signature), Op::Push(pub_key)];
+        match preimage {
+            Some(preimage) => ops.extend([Op::Push(preimage.to_vec()), Op::Num(1)]),
+            None => ops.push(Op::Num(0)),
+        }
+        self.set_witness(input, Script(ops));
         Ok(())
     }
 
-    pub fn verify(&mut self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
+    // 第 input 个输入的见证
+    pub fn witness(&self, input: usize) -> &Script {
+        self.witnesses.get(input).unwrap_or(&EMPTY_WITNESS)
+    }
+
+    // 设置第 input 个输入的见证，见证部分按输入的数量补齐
+    fn set_witness(&mut self, input: usize, witness: Script) {
+        self.witnesses.resize(self.vin.len(), Script::new());
+        self.witnesses[input] = witness;
+    }
+
+    // 执行每个输入的见证和被花费输出的锁定脚本。区块和交易池的验证走 validation::verify_scripts，那里统一并行
+    pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: previous transaction is not correct"));
-            }
-        }
+        let checks = ScriptChecks {
+            tx: self,
+            cache: SighashCache::new(self, &prev_txs)?,
+        };
+        Ok((0..self.vin.len()).all(|input| checks.check(input, None)))
+    }
 
-        let mut tx_copy = self.trim_copy();
+    // 对第 input 个输入签名，返回末尾附加了签名哈希类型的签名
+    fn create_signature(&self, input: usize, script_code: &Script, private_key: &[u8], hash_type: u8, cache: &SighashCache) -> Result<Vec<u8>> {
+        if !is_valid_hash_type(hash_type) {
+            return Err(ChainError::InvalidInput(format!("invalid signature hash type {:#04x}", hash_type)));
+        }
+        public_key(private_key)?;
+        let hash = self.signature_hash(input, script_code, hash_type, cache)?;
+        let mut signature = ed25519::signature(hash.as_bytes(), private_key).to_vec();
+        signature.push(hash_type);
+        Ok(signature)
+    }
 
-        for in_id in 0..self.vin.len() {
-            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[in_id].pub_key,
-                &self.vin[in_id].signature,
-                ) {
-                    return Ok(false);
-                }
+    // 第 input 个输入的签名哈希。设置 SIGHASH_V1 时使用版本 1，否则按签名哈希类型裁剪交易，
+    // 用被花费输出的锁定脚本代替该输入的解锁脚本，签名哈希类型本身也一起哈希
+    fn signature_hash(&self, input: usize, script_code: &Script, hash_type: u8, cache: &SighashCache) -> Result<String> {
+        if hash_type & SIGHASH_V1 != 0 {
+            return self.signature_hash_v1(input, script_code, hash_type, cache);
+        }
+        let mut tx_copy = self.trim_copy(input, hash_type)?;
+        tx_copy.id = String::new();
+        let signed = if hash_type & SIGHASH_ANYONECANPAY != 0 { 0 } else { input };
+        tx_copy.vin[signed].script_sig = script_code.clone();
+        sha256_hex(&(tx_copy, hash_type))
+    }
 
+    // 版本 1 签名哈希：除了输入的位置、sequence 和输出，还覆盖所有被花费输出的金额和锁定脚本，
+    // 与输入无关的部分取自 cache，不需要为每个输入重新序列化整笔交易
+    fn signature_hash_v1(&self, input: usize, script_code: &Script, hash_type: u8, cache: &SighashCache) -> Result<String> {
+        let vin = self
+            .vin
+            .get(input)
+            .ok_or_else(|| ChainError::InvalidInput(format!("transaction {} has no input {}", self.id, input)))?;
+        let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+        let base_type = hash_type & !(SIGHASH_ANYONECANPAY | SIGHASH_V1);
+        let single_output;
+        let outputs = match base_type {
+            SIGHASH_ALL => cache.outputs.as_str(),
+            SIGHASH_SINGLE => {
+                let out = self
+                    .vout
+                    .get(input)
+                    .ok_or_else(|| ChainError::InvalidInput(format!("SIGHASH_SINGLE input {} has no matching output", input)))?;
+                single_output = sha256_hex(out)?;
+                single_output.as_str()
+            }
+            _ => "",
+        };
+        fn covered(hash: &str, is_covered: bool) -> &str {
+            if is_covered { hash } else { "" }
         }
-        Ok(true)
+        let preimage = SighashPreimage {
+            hash_type,
+            lock_time: self.lock_time,
+            prevouts: covered(&cache.prevouts, !anyone_can_pay),
+            amounts: covered(&cache.amounts, !anyone_can_pay),
+            script_pubkeys: covered(&cache.script_pubkeys, !anyone_can_pay),
+            sequences: covered(&cache.sequences, !anyone_can_pay && base_type == SIGHASH_ALL),
+            outputs,
+            txid: &vin.txid,
+            vout: vin.vout,
+            value: cache.spent[input].value,
+            script_code,
+            sequence: vin.sequence,
+        };
+        sha256_hex(&preimage)
     }
 
-    fn trim_copy(&self) -> Transaction {
+    // 签名覆盖的交易副本：不含见证，再按签名哈希类型去掉不需要覆盖的输入和输出
+    fn trim_copy(&self, input: usize, hash_type: u8) -> Result<Transaction> {
         let mut vin = Vec::new();
         let mut vout = Vec::new();
 
         for v in &self.vin {
             vin.push( TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
-                signature: Vec::new(),
-                pub_key: Vec::new(),
+                vout: v.vout,
+                script_sig: Script::new(),
+                sequence: v.sequence,
             })
         }
 
         for v in &self.vout {
             vout.push( TXOutput {
                 value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
+                script_pubkey: v.script_pubkey.clone(),
             })
         }
 
-        Transaction {
+        if input >= vin.len() || !is_valid_hash_type(hash_type) {
+            return Err(ChainError::InvalidInput(format!("cannot sign input {} with hash type {:#04x}", input, hash_type)));
+        }
+        let base_type = hash_type & !SIGHASH_ANYONECANPAY;
+        if base_type == SIGHASH_SINGLE {
+            // 只覆盖同一位置的输出，之前的输出清空后占位
+            if input >= vout.len() {
+                return Err(ChainError::InvalidInput(format!("SIGHASH_SINGLE input {} has no matching output", input)));
+            }
+            vout.truncate(input + 1);
+            for out in &mut vout[..input] {
+                *out = TXOutput { value: Amount::ZERO, script_pubkey: Script::new() };
+            }
+        } else if base_type == SIGHASH_NONE {
+            vout.clear();
+        }
+        // 不覆盖全部输出时，其他输入的 sequence 也可以被修改
+        if base_type != SIGHASH_ALL {
+            for (index, v) in vin.iter_mut().enumerate() {
+                if index != input {
+                    v.sequence = 0;
+                }
+            }
+        }
+        if hash_type & SIGHASH_ANYONECANPAY != 0 {
+            vin = vec![vin.swap_remove(input)];
+        }
+
+        Ok(Transaction {
             id: self.id.clone(),
             vin,
             vout,
-        }
+            lock_time: self.lock_time,
+            witnesses: Vec::new(),
+        })
     }
 
-    pub fn hash(&mut self) -> Result<String> {
-        self.id = String::new();
-        let data = bincode::serialize(self)?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        Ok(hasher.result_str())
-    } 
+    // 交易 id 不包含见证，签名前后交易 id 不变，花费尚未签名的交易的后续交易也不会因为签名而失效
+    pub fn hash(&self) -> Result<String> {
+        sha256_hex(&(&self.vin, &self.vout, self.lock_time))
+    }
+
+    // 包含见证的交易哈希（wtxid），区块头通过见证 Merkle 根对它进行承诺
+    pub fn witness_hash(&self) -> Result<String> {
+        sha256_hex(&(&self.vin, &self.vout, self.lock_time, &self.witnesses))
+    }
+
+    pub fn new_utxo(from: &str, to: &str, amount: Amount, fee: Amount, wallets: &Wallets, bc: &UTXOSet, mempool: &Mempool) -> Result<Transaction> {
+        if wallets.get_wallet(to).is_none() {
+            return Err(ChainError::Wallet(format!("to wallet {} not found", to)));
+        };
+        Transaction::new_to_script(from, script_for_address(to)?, amount, fee, wallets, bc, mempool)
+    }
 
-    pub fn new_utxo(from: &str, to: &str, amount: i32, bc: &UTXOSet) -> Result<Transaction> {
+    // 用 from 钱包的输出向任意锁定脚本支付 amount，找零回到 from，锁定脚本的输出是第 0 个输出
+    pub fn new_to_script(from: &str, script_pubkey: Script, amount: Amount, fee: Amount, wallets: &Wallets, bc: &UTXOSet, mempool: &Mempool) -> Result<Transaction> {
         let mut vin = Vec::new();
 
-        let wallets = Wallets::new()?;
         let wallet = match wallets.get_wallet(from) {
             Some(w) => w,
-            None => return  Err(format_err!("from wallet not found")),
-        };
-
-        if let None = wallets.get_wallet(&to) {
-            return Err(format_err!("to wallet not found"));
+            None => return  Err(ChainError::Wallet(format!("from wallet {} not found", from))),
         };
 
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
 
-        let acc_v = bc.find_spendable_outputs(&pub_key_hash, amount)?;
+        // 输入总额减去输出总额就是手续费，不单独记录
+        let needed = amount
+            .checked_add(fee)
+            .filter(|needed| needed.is_valid())
+            .ok_or_else(|| ChainError::InvalidAmount(format!("amount {} plus fee {} is out of range", amount, fee)))?;
+        let acc_v = bc.find_spendable_outputs(&pub_key_hash, needed, mempool)?;
 
-        if acc_v.0 < amount {
+        if acc_v.0 < needed {
             error!("Not Enough Balance");
-            return Err(format_err!("Not Enough Balance: current balance {}", acc_v.0));
+            return Err(ChainError::InsufficientFunds { needed, available: acc_v.0 });
         }
 
         for tx in acc_v.1 {
@@ -147,22 +534,23 @@ impl Transaction {
                 let input = TXInput {
                     txid: tx.0.clone(),
                     vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
+                    script_sig: Script::new(),
+                    sequence: SEQUENCE_FINAL,
                 };
                 vin.push(input);
             }
         }
 
-        let mut vout = vec![TXOutput::new(
-            amount,
-            to.to_string()
-        )?];
+        let mut vout = vec![TXOutput {
+            value: amount,
+            script_pubkey,
+        }];
 
-        if acc_v.0 > amount {
+        let change = acc_v.0.checked_sub(needed).unwrap_or_default();
+        if change > Amount::ZERO {
             vout.push( 
                 TXOutput::new(
-                acc_v.0 - amount,
+                change,
                 from.to_string()
             )?)
         }
@@ -171,14 +559,102 @@ impl Transaction {
             id: String::new(),
             vin,
             vout,
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        tx.id = tx.hash()?;
+        let prev_txs = Transaction::find_prev_txs(&tx, bc, mempool)?;
+        tx.sign(&wallet.secret_key, prev_txs)?;
+        Ok(tx)
+    }
+
+    // 在一个数据输出中锚定任意数据，例如文档的哈希，只需要支付手续费
+    pub fn new_data(from: &str, data: &[u8], fee: Amount, wallets: &Wallets, bc: &UTXOSet, mempool: &Mempool) -> Result<Transaction> {
+        if data.len() > MAX_DATA_CARRIER_SIZE {
+            return Err(ChainError::InvalidInput(format!(
+                "data output can carry at most {} bytes, got {}",
+                MAX_DATA_CARRIER_SIZE,
+                data.len()
+            )));
+        }
+        Transaction::new_to_script(from, Script::data_carrier(data), Amount::ZERO, fee, wallets, bc, mempool)
+    }
+
+    // 花费哈希时间锁合约输出并支付给 to 钱包：提供原像时以接收方身份领取，否则以发送方身份在超时之后取回。
+    // 取回时交易的锁定时间设为合约的超时时间，因此在此之前不能进入区块
+    pub fn new_htlc_spend(prev_tx: &Transaction, vout: i32, preimage: Option<&[u8]>, to: &str, fee: Amount, wallets: &Wallets) -> Result<Transaction> {
+        let wallet = wallets
+            .get_wallet(to)
+            .ok_or_else(|| ChainError::Wallet(format!("to wallet {} not found", to)))?;
+        let prev_txs = HashMap::from([(prev_tx.id.clone(), prev_tx.clone())]);
+        let input = TXInput {
+            txid: prev_tx.id.clone(),
+            vout,
+            script_sig: Script::new(),
+            sequence: SEQUENCE_FINAL,
+        };
+        let prev_out = prev_output(&prev_txs, &input)?;
+        let htlc = prev_out.script_pubkey.htlc_params().ok_or_else(|| {
+            ChainError::InvalidInput(format!("output {}:{} is not a hash time-locked contract", prev_tx.id, vout))
+        })?;
+        let owner = if preimage.is_some() { htlc.recipient } else { htlc.sender };
+        if hash160(&wallet.public_key) != owner {
+            return Err(ChainError::Wallet(format!("wallet {} cannot spend output {}:{}", to, prev_tx.id, vout)));
+        }
+        let value = prev_out
+            .value
+            .checked_sub(fee)
+            .ok_or(ChainError::InsufficientFunds { needed: fee, available: prev_out.value })?;
+
+        let (lock_time, sequence) = match preimage {
+            Some(_) => (0, SEQUENCE_FINAL),
+            None => (htlc.timeout, SEQUENCE_FINAL - 1),
+        };
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { sequence, ..input }],
+            vout: vec![TXOutput::new(value, to.to_string())?],
+            lock_time,
+            witnesses: Vec::new(),
         };
         tx.id = tx.hash()?;
-        bc.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
+        tx.sign_htlc(0, &wallet.secret_key, preimage, &prev_txs)?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        if data == String::from("") {
+    // 被花费的交易可能还在交易池中
+    fn find_prev_txs(tx: &Transaction, bc: &UTXOSet, mempool: &Mempool) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            let prev_tx = match mempool.get(&vin.txid) {
+                Some(prev_tx) => prev_tx.clone(),
+                None => bc.blockchain.find_transaction(&vin.txid)?,
+            };
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        Ok(prev_txs)
+    }
+
+    // 按手续费率（每 1000 字节）付费，交易大小取决于选中的输入，因此反复构造直到手续费足够
+    pub fn new_utxo_with_feerate(from: &str, to: &str, amount: Amount, feerate: Amount, wallets: &Wallets, bc: &UTXOSet, mempool: &Mempool) -> Result<Transaction> {
+        let mut fee = Amount::ZERO;
+        loop {
+            let tx = Transaction::new_utxo(from, to, amount, fee, wallets, bc, mempool)?;
+            let required = fee_for_size(tx.size()?, feerate);
+            if required <= fee {
+                return Ok(tx);
+            }
+            fee = required;
+        }
+    }
+
+    pub fn new_coinbase(to: String, data: String, height: usize) -> Result<Transaction> {
+        Transaction::new_coinbase_with_value(to, data, height, 0, SUBSIDY)
+    }
+
+    // 矿工打包交易后，coinbase 可以领取区块奖励加上全部手续费。extra_nonce 写入 coinbase 输入，区块头的 nonce 用尽时由矿工增加
+    pub fn new_coinbase_with_value(to: String, mut data: String, height: usize, extra_nonce: u64, value: Amount) -> Result<Transaction> {
+        if data.is_empty() {
             data += &format!("Reward to '{}'", to);
         }
 
@@ -187,17 +663,342 @@ impl Transaction {
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                signature: Vec::new(),
-                pub_key: Vec::from(data.as_bytes()),
+                script_sig: Script(vec![Op::Push(coinbase_script(height, extra_nonce, data.as_bytes()))]),
+                sequence: SEQUENCE_FINAL,
             }],
-            vout: vec![TXOutput::new(100, to)?],
+            vout: vec![TXOutput::new(value, to)?],
+            lock_time: 0,
+            witnesses: Vec::new(),
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    // coinbase 输入中写入的数据
+    fn coinbase_data(&self) -> Option<&[u8]> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        match self.vin[0].script_sig.0.first() {
+            Some(Op::Push(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    // coinbase 中记录的区块高度，不是 coinbase 或格式不对时返回 None
+    pub fn coinbase_height(&self) -> Option<usize> {
+        let bytes: [u8; 8] = self.coinbase_data()?.get(..8)?.try_into().ok()?;
+        Some(u64::from_be_bytes(bytes) as usize)
+    }
+
+    // coinbase 中记录的额外随机数
+    pub fn coinbase_extra_nonce(&self) -> Option<u64> {
+        let bytes: [u8; 8] = self.coinbase_data()?.get(8..COINBASE_PREFIX_LEN)?.try_into().ok()?;
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    // 修改 coinbase 的额外随机数并重新计算交易 id，高度和数据保持不变
+    pub(crate) fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<()> {
+        let height = self
+            .coinbase_height()
+            .ok_or_else(|| ChainError::InvalidInput(format!("transaction {} is not a coinbase", self.id)))?;
+        let data = self.coinbase_data().and_then(|data| data.get(COINBASE_PREFIX_LEN..)).unwrap_or_default().to_vec();
+        self.vin[0].script_sig = Script(vec![Op::Push(coinbase_script(height, extra_nonce, &data))]);
+        self.id = self.hash()?;
+        Ok(())
+    }
+
+    // 序列化后的字节数，用于计算手续费率和区块大小
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::config::{spendable_config, temp_config};
+    use crate::validation::BlockRejection;
+    use crate::wallet::{htlc_script, multisig_address, multisig_redeem_script, Wallet};
+
+    #[test]
+    fn test_typed_errors() {
+        let config = temp_config("tx_errors");
+        let mut wallets = Wallets::new(&config.data_dir).unwrap();
+        let alice = wallets.create_wallet();
+        let bob = wallets.create_wallet();
+        let bc = Blockchain::create_in_memory(alice.clone(), &spendable_config()).unwrap();
+        let utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex().unwrap();
+        let mempool = Mempool::new();
+
+        assert!(matches!(
+            TXOutput::new(SUBSIDY, String::from("not an address")),
+            Err(ChainError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            Transaction::new_utxo("unknown", &bob, SUBSIDY, Amount::ZERO, &wallets, &utxo_set, &mempool),
+            Err(ChainError::Wallet(_))
+        ));
+        let too_much = Amount::from_coins(101);
+        match Transaction::new_utxo(&alice, &bob, too_much, Amount::ZERO, &wallets, &utxo_set, &mempool) {
+            Err(ChainError::InsufficientFunds { needed, available }) => {
+                assert_eq!((needed, available), (too_much, SUBSIDY));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // 格式错误的签名和未知的前序交易返回错误而不是 panic
+        let mut tx = Transaction::new_utxo(&alice, &bob, Amount::from_coins(10), Amount::ZERO, &wallets, &utxo_set, &mempool).unwrap();
+        let prev_txs = utxo_set.blockchain.get_prev_txs(&tx).unwrap();
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+        if let Op::Push(signature) = &mut tx.witnesses[0].0[0] {
+            signature.truncate(10);
+        }
+        assert!(!tx.verify(prev_txs).unwrap());
+        assert!(matches!(tx.verify(HashMap::new()), Err(ChainError::NotFound(_))));
+
+        std::fs::remove_dir_all(config.data_dir.root()).ok();
+    }
+
+    #[test]
+    fn test_multisig() {
+        let alice = Wallet::new();
+        let keys: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key.clone()).collect();
+        assert!(multisig_redeem_script(0, &pub_keys).is_err());
+        assert!(multisig_redeem_script(4, &pub_keys).is_err());
+        let redeem = multisig_redeem_script(2, &pub_keys).unwrap();
+        let address = multisig_address(&redeem).unwrap();
+        assert_eq!(script_for_address(&address).unwrap(), redeem.to_p2sh());
+
+        // alice 把创世奖励中的 40 支付到 2-of-3 多重签名地址
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut pay = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: genesis_cb.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(Amount::from_coins(40), address.clone()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        pay.id = pay.hash().unwrap();
+        bc.sign_transaction(&mut pay, &alice.secret_key).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+        bc.add_block(vec![cbtx, pay.clone()]).unwrap();
+        let utxo_set = UTXOSet { blockchain: bc };
+        let locked = utxo_set.find_utxo(&redeem.to_p2sh()).unwrap();
+        assert_eq!(locked.iter().map(|out| out.value).collect::<Vec<_>>(), vec![Amount::from_coins(40)]);
+
+        // 持有人依次添加签名，凑够两个之前无法通过验证
+        let mut spend = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: pay.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(Amount::from_coins(40), alice.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        spend.id = spend.hash().unwrap();
+        let prev_txs = utxo_set.blockchain.get_prev_txs(&spend).unwrap();
+        assert!(matches!(
+            spend.sign_multisig(0, &alice.secret_key, &redeem, &prev_txs),
+            Err(ChainError::Wallet(_))
+        ));
+        spend.sign_multisig(0, &keys[2].secret_key, &redeem, &prev_txs).unwrap();
+        assert!(!spend.verify(prev_txs.clone()).unwrap());
+        spend.sign_multisig(0, &keys[0].secret_key, &redeem, &prev_txs).unwrap();
+        assert!(spend.verify(prev_txs).unwrap());
+        assert_eq!(spend.witness(0).0.len(), 3);
+
+        let mut bc = utxo_set.blockchain;
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 2"), 2).unwrap();
+        bc.add_block(vec![cbtx, spend]).unwrap();
+    }
+
+    #[test]
+    fn test_sighash_flags() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let carol = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("block 1"), 1).unwrap();
+        bc.add_block(vec![cbtx]).unwrap();
+        let funds: Vec<Transaction> = bc.iter().map(|block| block.get_transaction()[0].clone()).collect();
+        let input = |prev: &Transaction| TXInput { txid: prev.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL };
+        let output = |coins, to: &Wallet| TXOutput::new(Amount::from_coins(coins), to.get_address()).unwrap();
+
+        // 众筹：alice 和 bob 先后加入各自的输入，ANYONECANPAY 的签名不受其他输入影响，但输出不能再修改
+        let mut tx = Transaction { id: String::new(), vin: vec![input(&funds[1])], vout: vec![output(150, &carol)], lock_time: 0, witnesses: Vec::new() };
+        let prev_txs = bc.get_prev_txs(&tx).unwrap();
+        tx.sign_input(0, &alice.secret_key, SIGHASH_ALL | SIGHASH_ANYONECANPAY, &prev_txs).unwrap();
+        tx.vin.push(input(&funds[0]));
+        let prev_txs = bc.get_prev_txs(&tx).unwrap();
+        tx.sign_input(1, &bob.secret_key, SIGHASH_ALL | SIGHASH_ANYONECANPAY, &prev_txs).unwrap();
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+        let mut changed = tx.clone();
+        changed.vout[0] = output(150, &alice);
+        assert!(!changed.verify(prev_txs.clone()).unwrap());
+
+        // SIGHASH_NONE 不覆盖输出
+        let mut tx = Transaction { id: String::new(), vin: vec![input(&funds[1])], vout: vec![output(100, &carol)], lock_time: 0, witnesses: Vec::new() };
+        let alice_prev = bc.get_prev_txs(&tx).unwrap();
+        tx.sign_input(0, &alice.secret_key, SIGHASH_NONE, &alice_prev).unwrap();
+        tx.vout[0] = output(100, &alice);
+        assert!(tx.verify(alice_prev.clone()).unwrap());
+
+        // SIGHASH_SINGLE 只覆盖同一位置的输出
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![input(&funds[1]), input(&funds[0])],
+            vout: vec![output(100, &alice), output(100, &bob)],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        tx.sign_input(0, &alice.secret_key, SIGHASH_SINGLE, &prev_txs).unwrap();
+        tx.sign_input(1, &bob.secret_key, SIGHASH_SINGLE, &prev_txs).unwrap();
+        tx.vout.push(output(0, &carol));
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+        tx.vout[1] = output(100, &carol);
+        assert!(!tx.verify(prev_txs.clone()).unwrap());
+        tx.vout.truncate(1);
+        assert!(matches!(tx.sign_input(1, &bob.secret_key, SIGHASH_SINGLE, &prev_txs), Err(ChainError::InvalidInput(_))));
+
+        // 未知的签名哈希类型既不能用于签名，也不能通过验证
+        assert!(matches!(tx.sign_input(0, &alice.secret_key, 0x04, &prev_txs), Err(ChainError::InvalidInput(_))));
+        let mut tx = Transaction { id: String::new(), vin: vec![input(&funds[1])], vout: vec![output(100, &carol)], lock_time: 0, witnesses: Vec::new() };
+        tx.sign_input(0, &alice.secret_key, SIGHASH_ALL, &alice_prev).unwrap();
+        assert!(tx.verify(alice_prev.clone()).unwrap());
+        if let Op::Push(signature) = &mut tx.witnesses[0].0[0] {
+            *signature.last_mut().unwrap() = 0x04;
+        }
+        assert!(!tx.verify(alice_prev).unwrap());
+    }
+
+    #[test]
+    fn test_sighash_v1() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("block 1"), 1).unwrap();
+        bc.add_block(vec![cbtx]).unwrap();
+        let funds: Vec<Transaction> = bc.iter().map(|block| block.get_transaction()[0].clone()).collect();
+        let input = |prev: &Transaction| TXInput { txid: prev.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL };
+
+        // 签名者拿到的前序交易谎报了金额：旧的签名哈希照样有效，版本 1 的签名则无法通过验证
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![input(&funds[1])],
+            vout: vec![TXOutput::new(Amount::from_coins(1), bob.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        let prev_txs = bc.get_prev_txs(&tx).unwrap();
+        let mut forged = prev_txs.clone();
+        forged.get_mut(&funds[1].id).unwrap().vout[0].value = Amount::from_coins(2);
+        tx.sign_input(0, &alice.secret_key, SIGHASH_ALL, &forged).unwrap();
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+        tx.sign_input(0, &alice.secret_key, SIGHASH_ALL | SIGHASH_V1, &forged).unwrap();
+        assert!(!tx.verify(prev_txs.clone()).unwrap());
+        tx.sign_input(0, &alice.secret_key, SIGHASH_ALL | SIGHASH_V1, &prev_txs).unwrap();
+        assert!(tx.verify(prev_txs).unwrap());
+
+        // 版本 1 同样支持 ANYONECANPAY：加入新的输入不影响已有签名
+        tx.vin.push(input(&funds[0]));
+        let prev_txs = bc.get_prev_txs(&tx).unwrap();
+        tx.sign_input(1, &bob.secret_key, SIGHASH_ALL | SIGHASH_V1, &prev_txs).unwrap();
+        assert!(!tx.verify(prev_txs.clone()).unwrap());
+        tx.vin.truncate(1);
+        tx.sign_input(0, &alice.secret_key, SIGHASH_ALL | SIGHASH_ANYONECANPAY | SIGHASH_V1, &bc.get_prev_txs(&tx).unwrap()).unwrap();
+        tx.vin.push(input(&funds[0]));
+        tx.sign_input(1, &bob.secret_key, SIGHASH_ALL | SIGHASH_V1, &prev_txs).unwrap();
+        assert!(tx.verify(prev_txs).unwrap());
+    }
+
+    #[test]
+    fn test_witness_txid() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+
+        // bob 在 alice 签名之前就构造并签好了花费其输出的交易
+        let mut pay = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: genesis_cb.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(Amount::from_coins(40), bob.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        pay.id = pay.hash().unwrap();
+        let mut child = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: pay.id.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL }],
+            vout: vec![TXOutput::new(Amount::from_coins(40), alice.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        child.id = child.hash().unwrap();
+        child.sign(&bob.secret_key, HashMap::from([(pay.id.clone(), pay.clone())])).unwrap();
+
+        // 签名只改变 wtxid，交易 id 不变，子交易仍然有效
+        let unsigned_wtxid = pay.witness_hash().unwrap();
+        bc.sign_transaction(&mut pay, &alice.secret_key).unwrap();
+        assert_eq!(pay.hash().unwrap(), pay.id);
+        assert_ne!(pay.witness_hash().unwrap(), unsigned_wtxid);
+        assert_ne!(pay.witness_hash().unwrap(), pay.id);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+        bc.add_block(vec![cbtx, pay, child]).unwrap();
+    }
+
+    #[test]
+    fn test_htlc() {
+        let config = temp_config("tx_htlc");
+        let mut wallets = Wallets::new(&config.data_dir).unwrap();
+        let alice = wallets.create_wallet();
+        let bob = wallets.create_wallet();
+        let bc = Blockchain::create_in_memory(alice.clone(), &spendable_config()).unwrap();
+        let mut utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex().unwrap();
+
+        // alice 锁定 40 个币，bob 凭原像领取，或者 alice 在高度 3 之后取回
+        let mut hasher = Sha256::new();
+        hasher.input(b"secret");
+        let mut hash = vec![0u8; 32];
+        hasher.result(&mut hash);
+        let script = htlc_script(&hash, &bob, &alice, 3).unwrap();
+        assert!(htlc_script(&hash[1..], &bob, &alice, 3).is_err());
+        let fund = Transaction::new_to_script(&alice, script, Amount::from_coins(40), Amount::ZERO, &wallets, &utxo_set, &Mempool::new()).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.clone(), String::from("block 1"), 1).unwrap();
+        utxo_set.blockchain.add_block(vec![cbtx, fund.clone()]).unwrap();
+        let bc = &utxo_set.blockchain;
+
+        // 只有合约中指定的一方可以花费，原像错误时签名检查失败
+        let fee = Amount::from_units(1000);
+        assert!(matches!(Transaction::new_htlc_spend(&fund, 0, Some(b"secret"), &alice, fee, &wallets), Err(ChainError::Wallet(_))));
+        assert!(matches!(Transaction::new_htlc_spend(&fund, 0, None, &bob, fee, &wallets), Err(ChainError::Wallet(_))));
+        let guess = Transaction::new_htlc_spend(&fund, 0, Some(b"guess"), &bob, fee, &wallets).unwrap();
+        let err = Mempool::new().accept(guess.clone(), bc).unwrap_err();
+        assert_eq!(err.rejection(), Some(&BlockRejection::InvalidSignature(guess.id)));
+
+        let claim = Transaction::new_htlc_spend(&fund, 0, Some(b"secret"), &bob, fee, &wallets).unwrap();
+        assert_eq!(claim.vout[0].value, Amount::from_coins(40).checked_sub(fee).unwrap());
+        Mempool::new().accept(claim, bc).unwrap();
+
+        // 超时之前不能取回
+        let refund = Transaction::new_htlc_spend(&fund, 0, None, &alice, fee, &wallets).unwrap();
+        let err = Mempool::new().accept(refund.clone(), bc).unwrap_err();
+        assert_eq!(err.rejection(), Some(&BlockRejection::NonFinal(refund.id.clone())));
+        for height in 2..=3 {
+            let cbtx = Transaction::new_coinbase(alice.clone(), format!("block {}", height), height).unwrap();
+            utxo_set.blockchain.add_block(vec![cbtx]).unwrap();
+        }
+        Mempool::new().accept(refund, &utxo_set.blockchain).unwrap();
+
+        std::fs::remove_dir_all(config.data_dir.root()).ok();
+    }
+}
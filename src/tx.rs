@@ -1,55 +1,103 @@
 
-use bitcoincash_addr::Address;
+use std::collections::BTreeMap;
+
 use log::debug;
 use serde::{Deserialize, Serialize};
-use crate::{errors::Result, wallet::hash_pub_key};
+use crate::{amount::Amount, errors::Result, script::Script, wallet::script_for_address};
 
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// 一笔交易中尚未花费的输出，按输出在原交易中的序号索引
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
+    pub height: usize, // 创建这些输出的区块高度
+    pub is_coinbase: bool, // 是否来自 coinbase 交易
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXInput {
+impl TXOutputs {
+    // coinbase 的输出需要等待 maturity 个区块之后才能花费
+    pub fn is_mature(&self, spend_height: usize, maturity: usize) -> bool {
+        !self.is_coinbase || spend_height >= self.height + maturity
+    }
+}
+
+// 区块花费掉的一个输出及其位置，同时保存输出所在交易的高度和类型，以便原样恢复
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpentOutput {
     pub txid: String,
     pub vout: i32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>
+    pub output: TXOutput,
+    pub height: usize,
+    pub is_coinbase: bool,
 }
 
+// 区块的撤销记录，按花费顺序保存被花费的输出，断开区块时据此恢复 UTXO 集
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
+}
+
+// 输入的 sequence 为该值时不启用相对时间锁，交易的锁定时间也只在存在其他值时生效
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+// 设置该位时不启用相对时间锁
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+// 设置该位时相对时间锁以时间计，否则以区块数计
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+// 相对时间锁的数值部分
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0xffff;
+// 以时间计的相对时间锁每个单位对应的毫秒数
+pub const SEQUENCE_LOCKTIME_GRANULARITY_MS: u128 = 512_000;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutput {
-    pub value: i32,
-    pub pub_key_hash: Vec<u8>,
+pub struct TXInput {
+    pub txid: String,
+    pub vout: i32,
+    pub script_sig: Script, // 只有 coinbase 在此写入区块高度等数据，其他输入必须为空，解锁脚本放在交易的见证中
+    pub sequence: u32, // 相对时间锁：被花费的输出确认之后还需要经过的区块数或时间
 }
 
 impl TXInput {
-    pub fn can_unlock_output_with(&self, unlocking_data: &[u8]) -> bool {
-        let mut pub_key_hash = self.pub_key.clone();
-        hash_pub_key(&mut pub_key_hash);
-        pub_key_hash == unlocking_data
+    // 相对时间锁，未启用时返回 None，否则返回是否以时间计以及数值
+    pub fn relative_lock(&self) -> Option<(bool, u32)> {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        Some((
+            self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0,
+            self.sequence & SEQUENCE_LOCKTIME_MASK,
+        ))
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TXOutput {
+    pub value: Amount,
+    pub script_pubkey: Script, // 锁定脚本，花费时需要由对应见证中的解锁脚本满足
+}
+
 
 
 impl TXOutput {
+    // 输出是否是支付给该公钥哈希的标准输出
     pub fn can_be_unlock_with(&self, unlocking_data:  &[u8]) -> bool {
-        self.pub_key_hash == unlocking_data
+        self.script_pubkey.pubkey_hash() == Some(unlocking_data)
     }
     
+    // 数据输出等无法花费的输出
+    pub fn is_unspendable(&self) -> bool {
+        self.script_pubkey.is_unspendable()
+    }
+
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
         debug!("lock: {}", address);
-        self.pub_key_hash = pub_key_hash;
+        self.script_pubkey = script_for_address(address)?;
         Ok(())
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
+    pub fn new(value: Amount, address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
-            pub_key_hash: Vec::new(),
+            script_pubkey: Script::new(),
         };
         txo.lock(&address)?;
         Ok(txo)
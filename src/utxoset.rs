@@ -1,11 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use log::info;
 
+use crate::amount::Amount;
 use crate::block::Block;
 use crate::blockchain::Blockchain;
-use crate::errors::Result;
-use crate::tx::TXOutputs;
+use crate::errors::{ChainError, Result};
+use crate::mempool::Mempool;
+use crate::script::Script;
+use crate::storage::{ChainStore, Column, WriteBatch};
+use crate::tx::{BlockUndo, SpentOutput, TXOutput, TXOutputs};
 
 pub struct UTXOSet {
     pub blockchain: Blockchain,
@@ -14,100 +18,127 @@ pub struct UTXOSet {
 impl UTXOSet {
 
     pub fn reindex(&self) -> Result<()> {
-        if let Err(_) = std::fs::remove_dir_all("data/utxos") {
-            info!("not exist any utxos to delete")
-        }
-        let db = sled::open("data/utxos")?;
+        let store = self.blockchain.store();
+        store.clear(Column::Utxos)?;
+        info!("rebuilding utxo set");
         let utxos = self.blockchain.find_utxo();
+        let mut batch = WriteBatch::new();
         for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
+            batch.put_utxos(&txid, &outs)?;
         }
-        Ok(())
+        store.write(batch)
     }
 
 
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+    // 连接区块时对 UTXO 集的修改：移除被花费的输出，加入新创建的输出，同时记录撤销数据
+    pub(crate) fn connect_block(store: &dyn ChainStore, block: &Block, batch: &mut WriteBatch) -> Result<()> {
+        let mut cache = UtxoCache::new(store);
+        let mut undo = BlockUndo::default();
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = bincode::deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_outputs)?)?;
-                    }
+                    let mut outs = cache
+                        .get(&vin.txid)?
+                        .ok_or_else(|| ChainError::Storage(format!("utxo {}:{} is missing", vin.txid, vin.vout)))?;
+                    let output = outs
+                        .outputs
+                        .remove(&vin.vout)
+                        .ok_or_else(|| ChainError::Storage(format!("utxo {}:{} is missing", vin.txid, vin.vout)))?;
+                    undo.spent.push(SpentOutput {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                        output,
+                        height: outs.height,
+                        is_coinbase: outs.is_coinbase,
+                    });
+                    cache.set(&vin.txid, outs);
                 }
             }
             let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
+                outputs: BTreeMap::new(),
+                height: block.get_header().height,
+                is_coinbase: tx.is_coinbase(),
             };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            for (index, out) in tx.vout.iter().enumerate() {
+                if !out.is_unspendable() {
+                    new_outputs.outputs.insert(index as i32, out.clone());
+                }
             }
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
+            cache.set(&tx.id, new_outputs);
         }
-        Ok(())
+        batch.put_undo(&block.get_hash(), &undo)?;
+        cache.flush(batch)
     }
 
-    pub fn count_transaction(&self) -> Result<i32> {
-        let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
+    // 断开区块时撤销修改：删除区块创建的输出，并按撤销记录恢复被花费的输出
+    pub fn disconnect_block(store: &dyn ChainStore, block: &Block, batch: &mut WriteBatch) -> Result<()> {
+        let undo = store
+            .get_undo(&block.get_hash())?
+            .ok_or_else(|| ChainError::Storage(format!("undo data for block {} is missing", block.get_hash())))?;
+        let mut cache = UtxoCache::new(store);
+        for tx in block.get_transaction() {
+            cache.set(&tx.id, TXOutputs::default());
         }
-        Ok(counter)
+        // 区块内创建又被花费的输出随所在交易一起删除，不需要恢复
+        for spent in undo.spent {
+            if block.get_transaction().iter().any(|tx| tx.id == spent.txid) {
+                continue;
+            }
+            let mut outs = cache.get(&spent.txid)?.unwrap_or(TXOutputs {
+                outputs: BTreeMap::new(),
+                height: spent.height,
+                is_coinbase: spent.is_coinbase,
+            });
+            outs.outputs.insert(spent.vout, spent.output);
+            cache.set(&spent.txid, outs);
+        }
+        cache.flush(batch)
+    }
+
+    pub fn count_transaction(&self) -> Result<i32> {
+        Ok(self.blockchain.store().scan(Column::Utxos)?.len() as i32)
     }
 
-    pub fn find_spendable_outputs(&self, address: &[u8], amount:i32) -> Result< (i32, HashMap<String, Vec<i32>>)> {
+    // 选择足够支付 amount 的输出，跳过已被交易池花费的输出和尚未成熟的 coinbase 输出，必要时使用交易池中尚未确认的输出
+    pub fn find_spendable_outputs(&self, address: &[u8], amount: Amount, mempool: &Mempool) -> Result<(Amount, HashMap<String, Vec<i32>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated: i32 = 0;
-
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) =kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].can_be_unlock_with(address) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+        let mut accumulated = Amount::ZERO;
+        let spend_height = self.blockchain.get_best_height()? + 1;
+        let maturity = self.blockchain.config().params.coinbase_maturity;
+
+        let mut candidates = Vec::new();
+        for (txid, outs) in self.blockchain.store().utxos()? {
+            if !outs.is_mature(spend_height, maturity) {
+                continue;
+            }
+            for (out_idx, out) in outs.outputs {
+                if !mempool.is_spent(&txid, out_idx) {
+                    candidates.push((txid.clone(), out_idx, out));
                 }
-                
+            }
+        }
+        candidates.extend(mempool.find_unspent(address));
+
+        for (txid, out_idx, out) in candidates {
+            // 交易至少需要一个输入，即使要支付的金额为 0
+            if out.can_be_unlock_with(address) && (accumulated < amount || unspent_outputs.is_empty()) {
+                accumulated = accumulated
+                    .checked_add(out.value)
+                    .ok_or_else(|| ChainError::InvalidAmount(String::from("balance of spendable outputs overflows")))?;
+                unspent_outputs.entry(txid).or_default().push(out_idx);
             }
         }
         Ok((accumulated, unspent_outputs))
     }
 
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-
-        let db = sled::open("data/utxos")?;
+    // 锁定脚本与 script_pubkey 相同的全部未花费输出
+    pub fn find_utxo(&self, script_pubkey: &Script) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
 
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-
-            for out in outs.outputs {
-                if out.can_be_unlock_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+        for (_, outs) in self.blockchain.store().utxos()? {
+            for out in outs.outputs.into_values() {
+                if out.script_pubkey == *script_pubkey {
+                    utxos.push(out)
                 }
             }
         }
@@ -115,3 +146,116 @@ impl UTXOSet {
     }
 }
 
+// 同一区块内可能多次修改同一笔交易的输出，先在内存中合并再统一写入
+struct UtxoCache<'a> {
+    store: &'a dyn ChainStore,
+    pending: HashMap<String, TXOutputs>,
+}
+
+impl<'a> UtxoCache<'a> {
+    fn new(store: &'a dyn ChainStore) -> UtxoCache<'a> {
+        UtxoCache {
+            store,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn get(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        match self.pending.get(txid) {
+            Some(outs) if outs.outputs.is_empty() => Ok(None),
+            Some(outs) => Ok(Some(outs.clone())),
+            None => self.store.get_utxos(txid),
+        }
+    }
+
+    // 输出全部花费后记为空，写入时删除该条目
+    fn set(&mut self, txid: &str, outs: TXOutputs) {
+        self.pending.insert(txid.to_string(), outs);
+    }
+
+    fn flush(self, batch: &mut WriteBatch) -> Result<()> {
+        for (txid, outs) in self.pending {
+            if outs.outputs.is_empty() {
+                batch.delete_utxos(&txid);
+            } else {
+                batch.put_utxos(&txid, &outs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::spendable_config;
+    use crate::transaction::Transaction;
+    use crate::tx::{TXInput, SEQUENCE_FINAL};
+    use crate::wallet::Wallet;
+
+    fn spend(from: &Wallet, prev: &Transaction, to: &str, value: u64) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: 0,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput::new(Amount::from_coins(value), to.to_string()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        tx.id = tx.hash().unwrap();
+        tx.sign(&from.secret_key, HashMap::from([(prev.id.clone(), prev.clone())])).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_disconnect_block() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let before = bc.store().utxos().unwrap();
+
+        // bob 在同一区块内把刚收到的输出再转回给 alice
+        let pay = spend(&alice, &genesis_cb, &bob.get_address(), 60);
+        let back = spend(&bob, &pay, &alice.get_address(), 50);
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("cb"), 1).unwrap();
+        let block = bc.add_block(vec![cbtx, pay.clone(), back]).unwrap();
+
+        let undo = bc.store().get_undo(&block.get_hash()).unwrap().unwrap();
+        assert_eq!(undo.spent.len(), 2);
+        assert_eq!(undo.spent[0].txid, genesis_cb.id);
+        assert_eq!(undo.spent[0].output, genesis_cb.vout[0]);
+
+        let mut batch = WriteBatch::new();
+        UTXOSet::disconnect_block(bc.store(), &block, &mut batch).unwrap();
+        bc.store().write(batch).unwrap();
+        assert_eq!(bc.store().utxos().unwrap(), before);
+    }
+
+    #[test]
+    fn test_data_output() {
+        let alice = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+
+        // 数据输出不进入 UTXO 集，重建索引后也一样
+        let mut anchor = spend(&alice, &genesis_cb, &alice.get_address(), 60);
+        anchor.vout.push(TXOutput { value: Amount::ZERO, script_pubkey: Script::data_carrier(b"document hash") });
+        anchor.id = anchor.hash().unwrap();
+        anchor.sign(&alice.secret_key, HashMap::from([(genesis_cb.id.clone(), genesis_cb.clone())])).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("cb"), 1).unwrap();
+        bc.add_block(vec![cbtx, anchor.clone()]).unwrap();
+        let outs = bc.store().get_utxos(&anchor.id).unwrap().unwrap();
+        assert_eq!(outs.outputs.keys().collect::<Vec<_>>(), vec![&0]);
+
+        let utxo_set = UTXOSet { blockchain: bc };
+        let indexed = utxo_set.blockchain.store().utxos().unwrap();
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.blockchain.store().utxos().unwrap(), indexed);
+        assert_eq!(utxo_set.blockchain.find_transaction(&anchor.id).unwrap().vout[1].script_pubkey.data(), Some(&b"document hash"[..]));
+    }
+}
@@ -0,0 +1,954 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader, BLOCK_VERSION};
+use crate::blockchain::Blockchain;
+use crate::config::ChainParams;
+use crate::errors::Result;
+use crate::pow::{compact_to_target, next_work_required};
+use crate::script::MAX_DATA_CARRIER_SIZE;
+use crate::sigcache::SignatureCache;
+use crate::storage::ChainStore;
+use crate::transaction::{ScriptChecks, Transaction, LOCKTIME_THRESHOLD};
+use rayon::prelude::*;
+use crate::tx::{TXInput, TXOutput, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_GRANULARITY_MS};
+
+// 区块时间戳最多可以比本地时间超前两小时
+const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000;
+
+// 序列化后的区块大小上限
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+// 区块被拒绝的具体原因，以 `ChainError::Validation` 返回，调用方可以通过 `err.rejection()` 进行匹配
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockRejection {
+    NoTransactions,
+    BlockTooLarge(usize),
+    FirstTxNotCoinbase,
+    MultipleCoinbase,
+    BadCoinbaseHeight { expected: usize, found: Option<usize> },
+    UnknownVersion(u32),
+    BadBits(u32),
+    BadProofOfWork,
+    BadBlockHash,
+    BadMerkleRoot,
+    BadWitnessRoot,
+    BadTxid(String),
+    DuplicateTransaction(String),
+    OverwritesUnspent(String),
+    NoInputs(String),
+    NoOutputs(String),
+    UnknownParent(String),
+    PrevHashMismatch { expected: String, found: String },
+    BadHeight { expected: usize, found: usize },
+    BadDifficulty { expected: u32, found: u32 },
+    BadTimestamp(u128),
+    MissingInput { txid: String, prev_txid: String, vout: i32 },
+    ImmatureCoinbase { txid: String, prev_txid: String, height: usize },
+    DoubleSpend { txid: String, prev_txid: String, vout: i32 },
+    InvalidSignature(String),
+    NonFinal(String),
+    SequenceLocked { txid: String, prev_txid: String, vout: i32 },
+    ValueOutOfRange(String),
+    BadDataOutput(String),
+    BadWitness(String),
+    OutputsExceedInputs { txid: String, input: Amount, output: Amount },
+    BadCoinbaseValue { value: Amount, max: Amount },
+}
+
+impl BlockRejection {
+    // 稳定的拒绝原因代码，便于程序匹配和记录日志
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlockRejection::NoTransactions => "bad-blk-length",
+            BlockRejection::BlockTooLarge(_) => "bad-blk-size",
+            BlockRejection::FirstTxNotCoinbase => "bad-cb-missing",
+            BlockRejection::MultipleCoinbase => "bad-cb-multiple",
+            BlockRejection::BadCoinbaseHeight { .. } => "bad-cb-height",
+            BlockRejection::UnknownVersion(_) => "bad-version",
+            BlockRejection::BadBits(_) => "bad-bits",
+            BlockRejection::BadProofOfWork => "high-hash",
+            BlockRejection::BadBlockHash => "bad-blk-hash",
+            BlockRejection::BadMerkleRoot => "bad-txnmrklroot",
+            BlockRejection::BadWitnessRoot => "bad-witness-merkle-match",
+            BlockRejection::BadTxid(_) => "bad-txid",
+            BlockRejection::DuplicateTransaction(_) => "bad-txns-duplicate",
+            BlockRejection::OverwritesUnspent(_) => "bad-txns-BIP30",
+            BlockRejection::NoInputs(_) => "bad-txns-vin-empty",
+            BlockRejection::NoOutputs(_) => "bad-txns-vout-empty",
+            BlockRejection::UnknownParent(_) => "prev-blk-not-found",
+            BlockRejection::PrevHashMismatch { .. } => "bad-prevblk",
+            BlockRejection::BadHeight { .. } => "bad-height",
+            BlockRejection::BadDifficulty { .. } => "bad-diffbits",
+            BlockRejection::BadTimestamp(_) => "time-invalid",
+            BlockRejection::MissingInput { .. } => "bad-txns-inputs-missingorspent",
+            BlockRejection::ImmatureCoinbase { .. } => "bad-txns-premature-spend-of-coinbase",
+            BlockRejection::DoubleSpend { .. } => "bad-txns-double-spend",
+            BlockRejection::InvalidSignature(_) => "bad-txns-signature",
+            BlockRejection::NonFinal(_) => "bad-txns-nonfinal",
+            BlockRejection::SequenceLocked { .. } => "non-BIP68-final",
+            BlockRejection::ValueOutOfRange(_) => "bad-txns-value-outofrange",
+            BlockRejection::BadDataOutput(_) => "bad-txns-datacarrier",
+            BlockRejection::BadWitness(_) => "bad-txns-witness",
+            BlockRejection::OutputsExceedInputs { .. } => "bad-txns-in-belowout",
+            BlockRejection::BadCoinbaseValue { .. } => "bad-cb-amount",
+        }
+    }
+}
+
+impl fmt::Display for BlockRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockRejection::NoTransactions => write!(f, "block contains no transactions"),
+            BlockRejection::BlockTooLarge(size) => {
+                write!(f, "block is {} bytes, more than the {} byte limit", size, MAX_BLOCK_SIZE)
+            }
+            BlockRejection::FirstTxNotCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockRejection::MultipleCoinbase => write!(f, "more than one coinbase transaction"),
+            BlockRejection::BadCoinbaseHeight { expected, found } => {
+                write!(f, "coinbase commits to height {:?} but the block is at {}", found, expected)
+            }
+            BlockRejection::UnknownVersion(version) => write!(f, "unknown block version {}", version),
+            BlockRejection::BadBits(bits) => write!(f, "invalid or too easy target bits {:08x}", bits),
+            BlockRejection::BadProofOfWork => write!(f, "block hash does not satisfy proof of work"),
+            BlockRejection::BadBlockHash => write!(f, "block hash does not match its header"),
+            BlockRejection::BadMerkleRoot => write!(f, "merkle root does not match the transactions"),
+            BlockRejection::BadWitnessRoot => write!(f, "witness merkle root does not match the transactions"),
+            BlockRejection::BadTxid(txid) => {
+                write!(f, "transaction {} has an id that does not match its contents", txid)
+            }
+            BlockRejection::DuplicateTransaction(txid) => {
+                write!(f, "transaction {} appears more than once in the block", txid)
+            }
+            BlockRejection::OverwritesUnspent(txid) => {
+                write!(f, "transaction {} has the same id as one with unspent outputs", txid)
+            }
+            BlockRejection::NoInputs(txid) => write!(f, "transaction {} has no inputs", txid),
+            BlockRejection::NoOutputs(txid) => write!(f, "transaction {} has no outputs", txid),
+            BlockRejection::UnknownParent(hash) => write!(f, "parent block {} is unknown", hash),
+            BlockRejection::PrevHashMismatch { expected, found } => {
+                write!(f, "previous block hash {} does not match tip {}", found, expected)
+            }
+            BlockRejection::BadHeight { expected, found } => {
+                write!(f, "block claims height {} but should be {}", found, expected)
+            }
+            BlockRejection::BadDifficulty { expected, found } => {
+                write!(f, "block bits {:08x} do not match required {:08x}", found, expected)
+            }
+            BlockRejection::BadTimestamp(timestamp) => {
+                write!(f, "block timestamp {} is out of range", timestamp)
+            }
+            BlockRejection::MissingInput { txid, prev_txid, vout } => {
+                write!(f, "transaction {} spends missing or spent output {}:{}", txid, prev_txid, vout)
+            }
+            BlockRejection::ImmatureCoinbase { txid, prev_txid, height } => {
+                write!(f, "transaction {} spends coinbase {} from height {} before it matures", txid, prev_txid, height)
+            }
+            BlockRejection::DoubleSpend { txid, prev_txid, vout } => {
+                write!(f, "transaction {} double spends output {}:{} within the block", txid, prev_txid, vout)
+            }
+            BlockRejection::InvalidSignature(txid) => {
+                write!(f, "transaction {} has an invalid signature", txid)
+            }
+            BlockRejection::NonFinal(txid) => write!(f, "transaction {} is locked until a later block", txid),
+            BlockRejection::SequenceLocked { txid, prev_txid, vout } => {
+                write!(f, "transaction {} spends {}:{} before its relative lock expires", txid, prev_txid, vout)
+            }
+            BlockRejection::ValueOutOfRange(txid) => {
+                write!(f, "transaction {} has an amount or total out of range", txid)
+            }
+            BlockRejection::BadDataOutput(txid) => {
+                write!(f, "transaction {} has a data output that is too large or carries value", txid)
+            }
+            BlockRejection::BadWitness(txid) => {
+                write!(f, "transaction {} has unlocking data outside its witnesses or witnesses that do not match its inputs", txid)
+            }
+            BlockRejection::OutputsExceedInputs { txid, input, output } => {
+                write!(f, "transaction {} spends {} but creates {}", txid, input, output)
+            }
+            BlockRejection::BadCoinbaseValue { value, max } => {
+                write!(f, "coinbase pays {} but at most {} is allowed", value, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockRejection {}
+
+// 区块头的独立检查，无需区块体即可校验和转发区块头
+pub fn check_block_header(header: &BlockHeader, params: &ChainParams) -> Result<()> {
+    if header.version != BLOCK_VERSION {
+        return Err(BlockRejection::UnknownVersion(header.version).into());
+    }
+    match (compact_to_target(header.bits), compact_to_target(params.pow_limit_bits)) {
+        (Some(target), Some(limit)) if target <= limit => {}
+        _ => return Err(BlockRejection::BadBits(header.bits).into()),
+    }
+    if !header.meets_target()? {
+        return Err(BlockRejection::BadProofOfWork.into());
+    }
+    Ok(())
+}
+
+// 不依赖链上状态的检查：区块结构、工作量证明和交易格式
+pub fn check_block(block: &Block, params: &ChainParams) -> Result<()> {
+    check_block_header(block.get_header(), params)?;
+    if block.get_header().hash()? != block.get_hash() {
+        return Err(BlockRejection::BadBlockHash.into());
+    }
+
+    let txs = block.get_transaction();
+    if txs.is_empty() {
+        return Err(BlockRejection::NoTransactions.into());
+    }
+    let size = bincode::serialized_size(block)? as usize;
+    if size > MAX_BLOCK_SIZE {
+        return Err(BlockRejection::BlockTooLarge(size).into());
+    }
+    if !txs[0].is_coinbase() {
+        return Err(BlockRejection::FirstTxNotCoinbase.into());
+    }
+    if txs[1..].iter().any(|tx| tx.is_coinbase()) {
+        return Err(BlockRejection::MultipleCoinbase.into());
+    }
+    // coinbase 必须写入区块高度，否则不同区块的 coinbase 可能有相同的交易 id
+    let height = block.get_header().height;
+    if txs[0].coinbase_height() != Some(height) {
+        return Err(BlockRejection::BadCoinbaseHeight {
+            expected: height,
+            found: txs[0].coinbase_height(),
+        }
+        .into());
+    }
+    if block.get_header().merkle_root != block.hash_transactions() {
+        return Err(BlockRejection::BadMerkleRoot.into());
+    }
+    // 交易 id 不包含见证，见证只能通过见证 Merkle 根与区块绑定
+    if block.get_header().witness_root != block.hash_witnesses()? {
+        return Err(BlockRejection::BadWitnessRoot.into());
+    }
+
+    let mut seen = HashSet::new();
+    for tx in txs {
+        check_transaction(tx)?;
+        if !seen.insert(tx.id.clone()) {
+            return Err(BlockRejection::DuplicateTransaction(tx.id.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+pub fn check_transaction(tx: &Transaction) -> Result<()> {
+    if tx.vin.is_empty() {
+        return Err(BlockRejection::NoInputs(tx.id.clone()).into());
+    }
+    if tx.vout.is_empty() {
+        return Err(BlockRejection::NoOutputs(tx.id.clone()).into());
+    }
+    // 每个输出以及输出总额都不能超过货币总量上限
+    if Amount::checked_sum(tx.vout.iter().map(|out| out.value)).is_none() {
+        return Err(BlockRejection::ValueOutOfRange(tx.id.clone()).into());
+    }
+    // 无法花费的输出只能是不带金额的数据输出，且数据不能超过 MAX_DATA_CARRIER_SIZE
+    let bad_data = |out: &TXOutput| match out.script_pubkey.data() {
+        Some(data) => data.len() > MAX_DATA_CARRIER_SIZE || out.value != Amount::ZERO,
+        None => out.is_unspendable(),
+    };
+    if tx.vout.iter().any(bad_data) {
+        return Err(BlockRejection::BadDataOutput(tx.id.clone()).into());
+    }
+    // 解锁脚本只能放在见证中，见证为空或者与输入一一对应；coinbase 没有见证，输入中写入的是区块高度等数据
+    let bad_witness = if tx.is_coinbase() {
+        !tx.witnesses.is_empty()
+    } else {
+        (!tx.witnesses.is_empty() && tx.witnesses.len() != tx.vin.len())
+            || tx.vin.iter().any(|vin| !vin.script_sig.0.is_empty())
+    };
+    if bad_witness {
+        return Err(BlockRejection::BadWitness(tx.id.clone()).into());
+    }
+    // 交易 id 不包含见证，见 Transaction::hash
+    if tx.hash()? != tx.id {
+        return Err(BlockRejection::BadTxid(tx.id.clone()).into());
+    }
+    Ok(())
+}
+
+// 相对父区块的区块头检查，侧链上的区块在保存之前也要通过
+pub fn check_header_contextual(header: &BlockHeader, parent: &BlockHeader, store: &dyn ChainStore, params: &ChainParams) -> Result<()> {
+    let expected_height = parent.height + 1;
+    if header.height != expected_height {
+        return Err(BlockRejection::BadHeight {
+            expected: expected_height,
+            found: header.height,
+        }
+        .into());
+    }
+    let expected_bits = next_work_required(store, parent, params)?;
+    if header.bits != expected_bits {
+        return Err(BlockRejection::BadDifficulty {
+            expected: expected_bits,
+            found: header.bits,
+        }
+        .into());
+    }
+    check_timestamp(header, parent)
+}
+
+// 依赖链上状态的检查：区块必须接在当前链尖之后，输入存在且未花费、签名和金额正确
+pub fn check_block_contextual(block: &Block, bc: &Blockchain) -> Result<()> {
+    if block.get_prev_hash() != bc.tip() {
+        return Err(BlockRejection::PrevHashMismatch {
+            expected: bc.tip().to_string(),
+            found: block.get_prev_hash(),
+        }
+        .into());
+    }
+
+    let store = bc.store();
+    let header = block.get_header();
+    let parent = store
+        .get_block(&header.prev_block_hash)?
+        .ok_or_else(|| BlockRejection::UnknownParent(header.prev_block_hash.clone()))?;
+    check_header_contextual(header, parent.get_header(), store, &bc.config().params)?;
+
+    let txs = block.get_transaction();
+    // 区块内较早的交易创建的输出可以被后面的交易花费
+    let mut created: HashMap<String, &Transaction> = HashMap::new();
+    let mut spent: HashSet<(String, i32)> = HashSet::new();
+    let mut fees = Amount::ZERO;
+    // 所有交易的脚本在其余检查都通过之后一起并行验证
+    let mut checks = Vec::new();
+
+    // 交易 id 与 UTXO 集中尚有未花费输出的交易相同时，连接区块会覆盖这些输出
+    for tx in txs {
+        if store.get_utxos(&tx.id)?.is_some() {
+            return Err(BlockRejection::OverwritesUnspent(tx.id.clone()).into());
+        }
+    }
+
+    for tx in &txs[1..] {
+        for vin in &tx.vin {
+            if !spent.insert((vin.txid.clone(), vin.vout)) {
+                return Err(BlockRejection::DoubleSpend {
+                    txid: tx.id.clone(),
+                    prev_txid: vin.txid.clone(),
+                    vout: vin.vout,
+                }
+                .into());
+            }
+        }
+        let (fee, spent_outputs) = check_tx_spends(tx, bc, &created, parent.get_header())?;
+        fees = fees
+            .checked_add(fee)
+            .filter(|fees| fees.is_valid())
+            .ok_or_else(|| BlockRejection::ValueOutOfRange(tx.id.clone()))?;
+        checks.push(ScriptChecks::new(tx, spent_outputs)?);
+        created.insert(tx.id.clone(), tx);
+    }
+    verify_scripts(&checks, bc.sig_cache())?;
+
+    let max = block_subsidy(header.height, &bc.config().params)
+        .checked_add(fees)
+        .ok_or_else(|| BlockRejection::ValueOutOfRange(txs[0].id.clone()))?;
+    check_coinbase_value(&txs[0], max)
+}
+
+// 某个高度的区块奖励，每 subsidy_halving_interval 个区块减半，减到 0 后不再发行
+pub fn block_subsidy(height: usize, params: &ChainParams) -> Amount {
+    let halvings = height / params.subsidy_halving_interval;
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_units(params.initial_subsidy.as_units() >> halvings)
+}
+
+// 交易的锁定时间是否已过：小于 LOCKTIME_THRESHOLD 时与区块高度比较，否则与父区块的时间戳比较；
+// 所有输入的 sequence 都是 SEQUENCE_FINAL 时锁定时间不生效
+pub fn is_final_tx(tx: &Transaction, height: usize, time: u128) -> bool {
+    if tx.lock_time == 0 {
+        return true;
+    }
+    let cutoff = if tx.lock_time < LOCKTIME_THRESHOLD { height as u128 } else { time };
+    if (tx.lock_time as u128) < cutoff {
+        return true;
+    }
+    tx.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
+}
+
+// 输入的相对时间锁是否已过。被花费的输出在 prev_height 高度确认，以区块数计时要求经过足够多的区块，
+// 以时间计时从确认区块的父区块时间戳开始计算，到交易所在区块的父区块时间戳为止
+fn check_sequence_lock(tx: &Transaction, vin: &TXInput, bc: &Blockchain, parent: &BlockHeader, prev_height: usize) -> Result<()> {
+    let (by_time, value) = match vin.relative_lock() {
+        Some(lock) => lock,
+        None => return Ok(()),
+    };
+    let passed = if by_time {
+        let start = bc.get_block_by_height(prev_height.saturating_sub(1))?.get_header().timestamp;
+        parent.timestamp.saturating_sub(start) >= value as u128 * SEQUENCE_LOCKTIME_GRANULARITY_MS
+    } else {
+        parent.height + 1 - prev_height >= value as usize
+    };
+    if !passed {
+        return Err(BlockRejection::SequenceLocked {
+            txid: tx.id.clone(),
+            prev_txid: vin.txid.clone(),
+            vout: vin.vout,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+// 校验交易可以进入 parent 之后的区块：锁定时间已过，花费的输出存在、已经成熟、相对时间锁已过、
+// 金额足够且签名正确，返回输入总额减去输出总额。
+// created 中是尚未写入 UTXO 集但可以被花费的交易，例如区块内较早的交易或交易池中的交易，它们视为在交易所在的区块中确认
+pub fn check_tx_inputs(
+    tx: &Transaction,
+    bc: &Blockchain,
+    created: &HashMap<String, &Transaction>,
+    parent: &BlockHeader,
+) -> Result<Amount> {
+    let (fee, spent) = check_tx_spends(tx, bc, created, parent)?;
+    verify_scripts(&[ScriptChecks::new(tx, spent)?], bc.sig_cache())?;
+    Ok(fee)
+}
+
+// 把所有交易的所有输入展开成一个列表后并行执行脚本，签名缓存中已有的签名不再验证。多笔交易验证失败时报告区块中最靠前的一笔
+fn verify_scripts(checks: &[ScriptChecks], sig_cache: &SignatureCache) -> Result<()> {
+    let inputs: Vec<(&ScriptChecks, usize)> = checks
+        .iter()
+        .flat_map(|check| (0..check.tx().vin.len()).map(move |input| (check, input)))
+        .collect();
+    match inputs.par_iter().find_first(|(check, input)| !check.check(*input, Some(sig_cache))) {
+        Some((check, _)) => Err(BlockRejection::InvalidSignature(check.tx().id.clone()).into()),
+        None => Ok(()),
+    }
+}
+
+// check_tx_inputs 中除了脚本以外的检查，返回手续费和每个输入花费的输出
+fn check_tx_spends(
+    tx: &Transaction,
+    bc: &Blockchain,
+    created: &HashMap<String, &Transaction>,
+    parent: &BlockHeader,
+) -> Result<(Amount, Vec<TXOutput>)> {
+    let spend_height = parent.height + 1;
+    if !is_final_tx(tx, spend_height, parent.timestamp) {
+        return Err(BlockRejection::NonFinal(tx.id.clone()).into());
+    }
+
+    let store = bc.store();
+    let maturity = bc.config().params.coinbase_maturity;
+    let mut input_value = Amount::ZERO;
+    let mut spent = Vec::with_capacity(tx.vin.len());
+    for vin in &tx.vin {
+        let (prev_out, prev_height): (Option<TXOutput>, usize) = match created.get(&vin.txid) {
+            Some(prev_tx) => (
+                prev_tx.vout.get(vin.vout as usize).filter(|out| !out.is_unspendable()).cloned(),
+                spend_height,
+            ),
+            None => match store.get_utxos(&vin.txid)? {
+                Some(outs) => {
+                    if !outs.is_mature(spend_height, maturity) {
+                        return Err(BlockRejection::ImmatureCoinbase {
+                            txid: tx.id.clone(),
+                            prev_txid: vin.txid.clone(),
+                            height: outs.height,
+                        }
+                        .into());
+                    }
+                    (outs.outputs.get(&vin.vout).cloned(), outs.height)
+                }
+                None => (None, spend_height),
+            },
+        };
+        let prev_out = prev_out.ok_or_else(|| BlockRejection::MissingInput {
+            txid: tx.id.clone(),
+            prev_txid: vin.txid.clone(),
+            vout: vin.vout,
+        })?;
+        check_sequence_lock(tx, vin, bc, parent, prev_height)?;
+        input_value = input_value
+            .checked_add(prev_out.value)
+            .filter(|value| value.is_valid())
+            .ok_or_else(|| BlockRejection::ValueOutOfRange(tx.id.clone()))?;
+        spent.push(prev_out);
+    }
+
+    let output_value = Amount::checked_sum(tx.vout.iter().map(|out| out.value))
+        .ok_or_else(|| BlockRejection::ValueOutOfRange(tx.id.clone()))?;
+    let fee = input_value.checked_sub(output_value).ok_or_else(|| {
+        BlockRejection::OutputsExceedInputs {
+            txid: tx.id.clone(),
+            input: input_value,
+            output: output_value,
+        }
+    })?;
+    Ok((fee, spent))
+}
+
+// coinbase 最多领取区块奖励加上区块内全部交易的手续费
+fn check_coinbase_value(coinbase: &Transaction, max: Amount) -> Result<()> {
+    let value = Amount::checked_sum(coinbase.vout.iter().map(|out| out.value))
+        .ok_or_else(|| BlockRejection::ValueOutOfRange(coinbase.id.clone()))?;
+    if value > max {
+        return Err(BlockRejection::BadCoinbaseValue { value, max }.into());
+    }
+    Ok(())
+}
+
+// 区块时间戳不能早于父区块，也不能超过当前时间太多，否则会干扰难度调整
+fn check_timestamp(header: &BlockHeader, parent: &BlockHeader) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    if header.timestamp < parent.timestamp || header.timestamp > now + MAX_FUTURE_BLOCK_TIME_MS {
+        return Err(BlockRejection::BadTimestamp(header.timestamp).into());
+    }
+    Ok(())
+}
+
+// 创世区块没有父区块，只做无上下文检查
+pub fn validate_genesis(block: &Block, params: &ChainParams) -> Result<()> {
+    check_block(block, params)?;
+    if block.get_header().height != 0 {
+        return Err(BlockRejection::BadHeight {
+            expected: 0,
+            found: block.get_header().height,
+        }
+        .into());
+    }
+    if block.get_header().bits != params.genesis_bits {
+        return Err(BlockRejection::BadDifficulty {
+            expected: params.genesis_bits,
+            found: block.get_header().bits,
+        }
+        .into());
+    }
+    check_coinbase_value(&block.get_transaction()[0], block_subsidy(0, params))
+}
+
+// 完整的区块校验流程，所有追加区块的路径都必须先通过这里
+pub fn validate_block(block: &Block, bc: &Blockchain) -> Result<()> {
+    check_block(block, &bc.config().params)?;
+    check_block_contextual(block, bc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::MAX_MONEY;
+    use crate::config::spendable_config;
+    use crate::errors::ChainError;
+    use crate::mempool::Mempool;
+    use crate::storage::WriteBatch;
+    use crate::tx::{TXOutputs, SEQUENCE_LOCKTIME_TYPE_FLAG};
+    use crate::transaction::SUBSIDY;
+    use crate::script::{Op, Script};
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::Wallet;
+
+    const BITS: u32 = 0x207fffff;
+
+    fn spend(bc: &Blockchain, from: &Wallet, prev: &Transaction, vout: i32, to: &str, value: u64) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput::new(Amount::from_coins(value), to.to_string()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        tx.id = tx.hash().unwrap();
+        bc.sign_transaction(&mut tx, &from.secret_key).unwrap();
+        tx
+    }
+
+    // 修改交易的锁定时间和所有输入的 sequence 之后重新签名
+    fn relock(bc: &Blockchain, from: &Wallet, tx: &Transaction, lock_time: u64, sequence: u32) -> Transaction {
+        let mut tx = tx.clone();
+        tx.lock_time = lock_time;
+        for vin in &mut tx.vin {
+            vin.sequence = sequence;
+        }
+        tx.id = tx.hash().unwrap();
+        bc.sign_transaction(&mut tx, &from.secret_key).unwrap();
+        tx
+    }
+
+    fn rejection(res: Result<()>) -> BlockRejection {
+        match res.unwrap_err() {
+            ChainError::Validation(rejection) => rejection,
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex().unwrap();
+        let bc = &utxo_set.blockchain;
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("cb"), 1).unwrap();
+
+        // 正常的转账区块
+        let pay = spend(bc, &alice, &genesis_cb, 0, &bob.get_address(), 40);
+        let ok = Block::new_block(vec![cbtx.clone(), pay.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        validate_block(&ok, bc).unwrap();
+
+        // 同一区块内重复花费同一个输出
+        let pay2 = spend(bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        let double = Block::new_block(vec![cbtx.clone(), pay.clone(), pay2], bc.tip().to_string(), 1, BITS).unwrap();
+        assert!(matches!(rejection(validate_block(&double, bc)), BlockRejection::DoubleSpend { .. }));
+
+        // 由非所有者签名的花费
+        let theft = spend(bc, &bob, &genesis_cb, 0, &bob.get_address(), 40);
+        let stolen = Block::new_block(vec![cbtx.clone(), theft.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(rejection(validate_block(&stolen, bc)), BlockRejection::InvalidSignature(theft.id));
+
+        // 难度与链上要求不一致
+        let harder = Block::new_block(vec![cbtx.clone()], bc.tip().to_string(), 1, 0x201fffff).unwrap();
+        assert_eq!(
+            rejection(validate_block(&harder, bc)),
+            BlockRejection::BadDifficulty { expected: BITS, found: 0x201fffff }
+        );
+
+        // 高度与父区块不连续
+        let cb5 = Transaction::new_coinbase(bob.get_address(), String::from("cb"), 5).unwrap();
+        let skipped = Block::new_block(vec![cb5], bc.tip().to_string(), 5, BITS).unwrap();
+        assert_eq!(
+            rejection(validate_block(&skipped, bc)),
+            BlockRejection::BadHeight { expected: 1, found: 5 }
+        );
+
+        // 父区块不是当前链尖
+        let orphan = Block::new_block(vec![cbtx], String::from("00"), 1, BITS).unwrap();
+        assert!(matches!(rejection(validate_block(&orphan, bc)), BlockRejection::PrevHashMismatch { .. }));
+    }
+
+    #[test]
+    fn test_unique_coinbase() {
+        let alice = Wallet::new();
+        let bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+
+        // 相同地址和数据的 coinbase 在不同高度的交易 id 不同，同一高度的额外随机数也会改变交易 id
+        let cb1 = Transaction::new_coinbase(alice.get_address(), String::from("cb"), 1).unwrap();
+        let cb2 = Transaction::new_coinbase(alice.get_address(), String::from("cb"), 2).unwrap();
+        assert_ne!(cb1.id, cb2.id);
+        let bumped = Transaction::new_coinbase_with_value(alice.get_address(), String::from("cb"), 1, 7, SUBSIDY).unwrap();
+        assert_ne!(bumped.id, cb1.id);
+        assert_eq!(bumped.coinbase_height(), Some(1));
+        assert_eq!((cb1.coinbase_extra_nonce(), bumped.coinbase_extra_nonce()), (Some(0), Some(7)));
+
+        // coinbase 中的高度必须与区块高度一致
+        let wrong = Block::new_block(vec![cb2], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(
+            rejection(validate_block(&wrong, &bc)),
+            BlockRejection::BadCoinbaseHeight { expected: 1, found: Some(2) }
+        );
+
+        // 交易 id 与尚未花费完的交易相同时拒绝，避免覆盖原有输出
+        let mut batch = WriteBatch::new();
+        batch.put_utxos(&cb1.id, &TXOutputs::default()).unwrap();
+        bc.store().write(batch).unwrap();
+        let dup = Block::new_block(vec![cb1.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(rejection(validate_block(&dup, &bc)), BlockRejection::OverwritesUnspent(cb1.id));
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let alice = Wallet::new();
+        let mut config = spendable_config();
+        config.params.coinbase_maturity = 2;
+        let bc = Blockchain::create_in_memory(alice.get_address(), &config).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut utxo_set = UTXOSet { blockchain: bc };
+
+        // 高度 1 还不能花费创世区块的奖励，选币时也不会选中
+        let pkh = genesis_cb.vout[0].script_pubkey.pubkey_hash().unwrap().to_vec();
+        assert_eq!(utxo_set.find_spendable_outputs(&pkh, Amount::from_coins(10), &Mempool::new()).unwrap().0, Amount::ZERO);
+        let pay = spend(&utxo_set.blockchain, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+        let early = Block::new_block(vec![cbtx.clone(), pay.clone()], utxo_set.blockchain.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(
+            rejection(validate_block(&early, &utxo_set.blockchain)),
+            BlockRejection::ImmatureCoinbase { txid: pay.id.clone(), prev_txid: genesis_cb.id.clone(), height: 0 }
+        );
+
+        // 经过两个区块之后可以花费
+        utxo_set.blockchain.add_block(vec![cbtx]).unwrap();
+        assert_eq!(utxo_set.find_spendable_outputs(&pkh, Amount::from_coins(10), &Mempool::new()).unwrap().0, SUBSIDY);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 2"), 2).unwrap();
+        let bc = &utxo_set.blockchain;
+        let ok = Block::new_block(vec![cbtx, pay], bc.tip().to_string(), 2, BITS).unwrap();
+        validate_block(&ok, bc).unwrap();
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let params = ChainParams::regtest();
+        assert_eq!(block_subsidy(0, &params), Amount::from_coins(100));
+        assert_eq!(block_subsidy(149, &params), Amount::from_coins(100));
+        assert_eq!(block_subsidy(150, &params), Amount::from_coins(50));
+        assert_eq!(block_subsidy(300, &params), Amount::from_coins(25));
+        assert_eq!(block_subsidy(450, &params), Amount::from_units(1_250_000_000));
+        assert_eq!(block_subsidy(150 * 64, &params), Amount::ZERO);
+        assert_eq!(block_subsidy(usize::MAX, &params), Amount::ZERO);
+        // 奖励按最小单位减半，发行总量略少于 2 * 100 * 150 个币
+        assert!(params.max_supply() < Amount::from_coins(2 * 100 * 150));
+        assert!(params.max_supply() > Amount::from_coins(2 * 100 * 150 - 1));
+        assert!(ChainParams::main().max_supply() <= MAX_MONEY);
+    }
+
+    #[test]
+    fn test_reject_bad_coinbase() {
+        let alice = Wallet::new();
+        let bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+
+        let mut greedy = Transaction::new_coinbase(alice.get_address(), String::from("greedy"), 1).unwrap();
+        let greedy_value = SUBSIDY.checked_add(Amount::from_units(1)).unwrap();
+        greedy.vout[0].value = greedy_value;
+        greedy.id = greedy.hash().unwrap();
+        let block = Block::new_block(vec![greedy], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(
+            rejection(validate_block(&block, &bc)),
+            BlockRejection::BadCoinbaseValue { value: greedy_value, max: SUBSIDY }
+        );
+
+        // 转账只支付 40，剩余的 60 作为手续费归矿工
+        let pay = spend(&bc, &alice, &bc.iter().next().unwrap().get_transaction()[0], 0, &alice.get_address(), 40);
+        let cbtx = Transaction::new_coinbase_with_value(alice.get_address(), String::from("fees"), 1, 0, Amount::from_coins(160)).unwrap();
+        let block = Block::new_block(vec![cbtx, pay.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        validate_block(&block, &bc).unwrap();
+        let cbtx = Transaction::new_coinbase_with_value(alice.get_address(), String::from("fees"), 1, 0, Amount::from_coins(161)).unwrap();
+        let block = Block::new_block(vec![cbtx, pay], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(
+            rejection(validate_block(&block, &bc)),
+            BlockRejection::BadCoinbaseValue { value: Amount::from_coins(161), max: Amount::from_coins(160) }
+        );
+    }
+
+    #[test]
+    fn test_script_lock_time() {
+        let alice = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+
+        // 交易锁定时间达到 3 之前不能花费的输出，之后按支付到公钥哈希的方式解锁
+        let mut locked = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        let pkh = locked.vout[0].script_pubkey.pubkey_hash().unwrap().to_vec();
+        let mut lock = vec![Op::Num(3), Op::CheckLockTimeVerify, Op::Drop];
+        lock.extend(Script::pay_to_pubkey_hash(&pkh).0);
+        locked.vout[0].script_pubkey = Script(lock);
+        locked.id = locked.hash().unwrap();
+        bc.sign_transaction(&mut locked, &alice.secret_key).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+        bc.add_block(vec![cbtx, locked.clone()]).unwrap();
+        bc.add_block(vec![Transaction::new_coinbase(alice.get_address(), String::from("block 2"), 2).unwrap()]).unwrap();
+
+        // 交易的锁定时间早于脚本要求，或者输入的 sequence 使锁定时间不生效时，脚本执行失败
+        let unlock = spend(&bc, &alice, &locked, 0, &alice.get_address(), 40);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 3"), 3).unwrap();
+        for (lock_time, sequence) in [(2, 0), (3, SEQUENCE_FINAL)] {
+            let tx = relock(&bc, &alice, &unlock, lock_time, sequence);
+            let block = Block::new_block(vec![cbtx.clone(), tx.clone()], bc.tip().to_string(), 3, BITS).unwrap();
+            assert_eq!(rejection(validate_block(&block, &bc)), BlockRejection::InvalidSignature(tx.id));
+        }
+
+        // 锁定时间为 3 的交易只能进入高度 4 及之后的区块
+        let unlock = relock(&bc, &alice, &unlock, 3, 0);
+        let early = Block::new_block(vec![cbtx.clone(), unlock.clone()], bc.tip().to_string(), 3, BITS).unwrap();
+        assert_eq!(rejection(validate_block(&early, &bc)), BlockRejection::NonFinal(unlock.id.clone()));
+
+        bc.add_block(vec![cbtx]).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 4"), 4).unwrap();
+        let ok = Block::new_block(vec![cbtx, unlock], bc.tip().to_string(), 4, BITS).unwrap();
+        validate_block(&ok, &bc).unwrap();
+    }
+
+    #[test]
+    fn test_lock_time() {
+        let alice = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let pay = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+
+        // 按高度锁定：高度 1 的区块和交易池都不接受
+        let by_height = relock(&bc, &alice, &pay, 1, 0);
+        let early = Block::new_block(vec![cbtx.clone(), by_height.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(rejection(validate_block(&early, &bc)), BlockRejection::NonFinal(by_height.id.clone()));
+        let err = Mempool::new().accept(by_height.clone(), &bc).unwrap_err();
+        assert_eq!(err.rejection(), Some(&BlockRejection::NonFinal(by_height.id.clone())));
+
+        // 按时间锁定：与父区块的时间戳比较
+        let parent_time = bc.get_tip_header().unwrap().timestamp as u64;
+        assert!(parent_time >= LOCKTIME_THRESHOLD);
+        let by_time = relock(&bc, &alice, &pay, parent_time, 0);
+        let early = Block::new_block(vec![cbtx.clone(), by_time.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(rejection(validate_block(&early, &bc)), BlockRejection::NonFinal(by_time.id.clone()));
+        let by_time = relock(&bc, &alice, &pay, parent_time - 1, 0);
+        let ok = Block::new_block(vec![cbtx.clone(), by_time], bc.tip().to_string(), 1, BITS).unwrap();
+        validate_block(&ok, &bc).unwrap();
+
+        // 所有输入的 sequence 都是 SEQUENCE_FINAL 时忽略锁定时间
+        let final_seq = relock(&bc, &alice, &pay, 1, SEQUENCE_FINAL);
+        let ok = Block::new_block(vec![cbtx.clone(), final_seq], bc.tip().to_string(), 1, BITS).unwrap();
+        validate_block(&ok, &bc).unwrap();
+
+        bc.add_block(vec![cbtx]).unwrap();
+        Mempool::new().accept(by_height, &bc).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_lock() {
+        let alice = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let pay = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 1"), 1).unwrap();
+
+        // 创世区块的输出需要再经过 2 个区块才能花费
+        let relative = relock(&bc, &alice, &pay, 0, 2);
+        let early = Block::new_block(vec![cbtx.clone(), relative.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        assert_eq!(
+            rejection(validate_block(&early, &bc)),
+            BlockRejection::SequenceLocked { txid: relative.id.clone(), prev_txid: genesis_cb.id.clone(), vout: 0 }
+        );
+
+        // 同一区块内创建的输出视为刚刚确认
+        let mut child = Transaction {
+            id: String::new(),
+            vin: vec![TXInput { txid: pay.id.clone(), vout: 0, script_sig: Script::new(), sequence: 1 }],
+            vout: vec![TXOutput::new(Amount::from_coins(40), alice.get_address()).unwrap()],
+            lock_time: 0,
+            witnesses: Vec::new(),
+        };
+        child.id = child.hash().unwrap();
+        child.sign(&alice.secret_key, HashMap::from([(pay.id.clone(), pay.clone())])).unwrap();
+        let chained = Block::new_block(vec![cbtx.clone(), pay, child.clone()], bc.tip().to_string(), 1, BITS).unwrap();
+        assert!(matches!(rejection(validate_block(&chained, &bc)), BlockRejection::SequenceLocked { .. }));
+
+        // 以时间计的锁在区块时间戳经过 512 秒之前不满足
+        bc.add_block(vec![cbtx]).unwrap();
+        let cbtx = Transaction::new_coinbase(alice.get_address(), String::from("block 2"), 2).unwrap();
+        let by_time = relock(&bc, &alice, &relative, 0, SEQUENCE_LOCKTIME_TYPE_FLAG | 1);
+        let early = Block::new_block(vec![cbtx.clone(), by_time.clone()], bc.tip().to_string(), 2, BITS).unwrap();
+        assert!(matches!(rejection(validate_block(&early, &bc)), BlockRejection::SequenceLocked { .. }));
+
+        let ok = Block::new_block(vec![cbtx, relative], bc.tip().to_string(), 2, BITS).unwrap();
+        validate_block(&ok, &bc).unwrap();
+    }
+
+    #[test]
+    fn test_reject_bad_data_output() {
+        let alice = Wallet::new();
+        let bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut tx = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        tx.vout.push(TXOutput { value: Amount::ZERO, script_pubkey: Script::data_carrier(&[7; MAX_DATA_CARRIER_SIZE]) });
+        tx.id = tx.hash().unwrap();
+        check_transaction(&tx).unwrap();
+
+        // 数据过长、携带金额或者不是标准的数据输出
+        for (data, value, extra) in [(MAX_DATA_CARRIER_SIZE + 1, 0, vec![]), (1, 1, vec![]), (1, 0, vec![Op::Num(1)])] {
+            let mut script = Script::data_carrier(&vec![7; data]);
+            script.0.extend(extra);
+            tx.vout[1] = TXOutput { value: Amount::from_coins(value), script_pubkey: script };
+            tx.id = tx.hash().unwrap();
+            assert_eq!(rejection(check_transaction(&tx)), BlockRejection::BadDataOutput(tx.id.clone()));
+        }
+    }
+
+    #[test]
+    fn test_parallel_script_checks() {
+        let alice = Wallet::new();
+        let bob = Wallet::new();
+        let mut bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+
+        // 把创世奖励拆成四个输出，在同一个区块中分别花费
+        let mut fan_out = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 10);
+        fan_out.vout = vec![fan_out.vout[0].clone(); 4];
+        fan_out.id = fan_out.hash().unwrap();
+        bc.sign_transaction(&mut fan_out, &alice.secret_key).unwrap();
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("block 1"), 1).unwrap();
+        bc.add_block(vec![cbtx, fan_out.clone()]).unwrap();
+
+        // 交易池接受交易时验证通过的签名进入缓存
+        let spends: Vec<Transaction> = (0..4).map(|vout| spend(&bc, &alice, &fan_out, vout, &bob.get_address(), 10)).collect();
+        let mut mempool = Mempool::new();
+        let cached = bc.sig_cache().len();
+        for tx in &spends {
+            mempool.accept(tx.clone(), &bc).unwrap();
+        }
+        assert_eq!(bc.sig_cache().len(), cached + spends.len());
+
+        // 所有输入并行验证，报告的是签名错误的那一笔
+        let theft = spend(&bc, &bob, &fan_out, 2, &alice.get_address(), 10);
+        let cbtx = Transaction::new_coinbase(bob.get_address(), String::from("block 2"), 2).unwrap();
+        let txs = [vec![cbtx.clone()], spends[..2].to_vec(), vec![theft.clone()], spends[3..].to_vec()].concat();
+        let block = Block::new_block(txs, bc.tip().to_string(), 2, BITS).unwrap();
+        assert_eq!(rejection(validate_block(&block, &bc)), BlockRejection::InvalidSignature(theft.id));
+
+        // 连接区块时交易池接受交易时缓存的签名全部命中，不再重复验证，也不会再次写入
+        let hits = bc.sig_cache().hits();
+        bc.add_block([vec![cbtx], spends.clone()].concat()).unwrap();
+        assert_eq!(bc.sig_cache().hits(), hits + spends.len());
+        assert_eq!(bc.sig_cache().len(), cached + spends.len());
+    }
+
+    #[test]
+    fn test_reject_bad_witness() {
+        let alice = Wallet::new();
+        let bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let tx = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        check_transaction(&tx).unwrap();
+
+        // 解锁脚本放在输入中
+        let mut moved = tx.clone();
+        moved.vin[0].script_sig = moved.witnesses.remove(0);
+        moved.id = moved.hash().unwrap();
+        assert_eq!(rejection(check_transaction(&moved)), BlockRejection::BadWitness(moved.id.clone()));
+
+        // 见证的数量与输入不一致
+        let mut extra = tx.clone();
+        extra.witnesses.push(Script::new());
+        assert_eq!(rejection(check_transaction(&extra)), BlockRejection::BadWitness(tx.id.clone()));
+
+        // coinbase 不能带见证
+        let mut cbtx = genesis_cb.clone();
+        cbtx.witnesses.push(Script::new());
+        assert_eq!(rejection(check_transaction(&cbtx)), BlockRejection::BadWitness(cbtx.id.clone()));
+    }
+
+    #[test]
+    fn test_reject_out_of_range_value() {
+        let alice = Wallet::new();
+        let bc = Blockchain::create_in_memory(alice.get_address(), &spendable_config()).unwrap();
+        let genesis_cb = bc.iter().next().unwrap().get_transaction()[0].clone();
+
+        // 单个输出超过上限
+        let mut tx = spend(&bc, &alice, &genesis_cb, 0, &alice.get_address(), 40);
+        tx.vout[0].value = MAX_MONEY.checked_add(Amount::from_units(1)).unwrap();
+        tx.id = tx.hash().unwrap();
+        assert_eq!(rejection(check_transaction(&tx)), BlockRejection::ValueOutOfRange(tx.id.clone()));
+
+        // 每个输出都合法，但总额超过上限
+        tx.vout[0].value = MAX_MONEY;
+        tx.vout.push(tx.vout[0].clone());
+        tx.id = tx.hash().unwrap();
+        assert_eq!(rejection(check_transaction(&tx)), BlockRejection::ValueOutOfRange(tx.id.clone()));
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
-use crate::errors::Result;
+use crate::config::DataDir;
+use crate::errors::{ChainError, Result};
+use crate::script::{Script, MAX_PUBKEYS_PER_MULTISIG};
 use bitcoincash_addr::{Address, HashType, Scheme};
 
 use crypto::digest::Digest;
@@ -19,7 +22,7 @@ pub struct Wallet {
 }
 
 impl Wallet {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         OsRng.fill_bytes(&mut key);
         let (secret_key, public_key) = ed25519::keypair(&key);
@@ -32,7 +35,7 @@ impl Wallet {
         }
     }
 
-    fn get_address(&self) -> String {
+    pub(crate) fn get_address(&self) -> String {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
         let address = Address{
@@ -45,31 +48,93 @@ impl Wallet {
     }
 }
 
+// m-of-n 多重签名的赎回脚本，公钥顺序决定了签名的顺序
+pub fn multisig_redeem_script(m: usize, pub_keys: &[Vec<u8>]) -> Result<Script> {
+    if m == 0 || m > pub_keys.len() || pub_keys.len() > MAX_PUBKEYS_PER_MULTISIG {
+        return Err(ChainError::InvalidInput(format!(
+            "invalid {}-of-{} multisig, at most {} keys are allowed",
+            m,
+            pub_keys.len(),
+            MAX_PUBKEYS_PER_MULTISIG
+        )));
+    }
+    if let Some(key) = pub_keys.iter().find(|key| key.len() != 32) {
+        return Err(ChainError::InvalidInput(format!("invalid public key of {} bytes", key.len())));
+    }
+    Ok(Script::multisig(m, pub_keys))
+}
+
+// 多重签名地址是赎回脚本哈希的 CashAddr 编码，与 Base58 编码的单签地址区分开
+pub fn multisig_address(redeem_script: &Script) -> Result<String> {
+    let address = Address {
+        body: hash160(&redeem_script.to_bytes()),
+        scheme: Scheme::CashAddr,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    address
+        .encode()
+        .map_err(|_| ChainError::InvalidInput(String::from("cannot encode multisig address")))
+}
+
+// 哈希时间锁合约脚本：recipient 提供 sha256 哈希为 hash 的原像即可领取，sender 在 timeout 之后可以取回。
+// timeout 与交易的锁定时间含义相同，小于 LOCKTIME_THRESHOLD 时是区块高度，否则是以毫秒计的时间戳
+pub fn htlc_script(hash: &[u8], recipient: &str, sender: &str, timeout: u64) -> Result<Script> {
+    if hash.len() != 32 {
+        return Err(ChainError::InvalidInput(format!("hash lock must be 32 bytes, got {}", hash.len())));
+    }
+    let recipient_script = script_for_address(recipient)?;
+    let sender_script = script_for_address(sender)?;
+    match (recipient_script.pubkey_hash(), sender_script.pubkey_hash()) {
+        (Some(recipient_pkh), Some(sender_pkh)) => Ok(Script::htlc(hash, recipient_pkh, sender_pkh, timeout)),
+        (None, _) => Err(ChainError::InvalidAddress(recipient.to_string())),
+        (_, None) => Err(ChainError::InvalidAddress(sender.to_string())),
+    }
+}
+
+// 地址对应的锁定脚本：CashAddr 脚本地址是多重签名的脚本哈希，其余都是公钥哈希
+pub fn script_for_address(address: &str) -> Result<Script> {
+    let decoded = Address::decode(address).map_err(|_| ChainError::InvalidAddress(address.to_string()))?;
+    match (&decoded.scheme, &decoded.hash_type) {
+        (Scheme::CashAddr, HashType::Script) => Ok(Script::pay_to_script_hash(&decoded.body)),
+        _ => Ok(Script::pay_to_pubkey_hash(&decoded.body)),
+    }
+}
+
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
+    *pub_key = hash160(pub_key);
+}
+
+// 先 sha256 再 ripemd160，输入可以是任意长度
+pub fn hash160(data: &[u8]) -> Vec<u8> {
+    let mut sha = [0u8; 32];
     let mut hasher1 = Sha256::new();
-    hasher1.input(pub_key);
-    hasher1.result(pub_key);
+    hasher1.input(data);
+    hasher1.result(&mut sha);
+    let mut hash = vec![0u8; 20];
     let mut hasher2 = Ripemd160::new();
-    hasher2.input(&pub_key);
-    pub_key.resize(20, 0);
-    hasher2.result(pub_key);
+    hasher2.input(&sha);
+    hasher2.result(&mut hash);
+    hash
 }
 
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    path: PathBuf,
 }
 
 impl Wallets{
-    pub fn new() -> Result<Wallets> {
+    pub fn new(data_dir: &DataDir) -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            path: data_dir.wallets(),
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&wlt.path)?;
         for item in db.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
+            let wallet = bincode::deserialize(&i.1)?;
             wlt.wallets.insert(address, wallet);
         }
         drop(db);
@@ -86,7 +151,7 @@ impl Wallets{
 
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone())
         }
         addresses
@@ -97,7 +162,7 @@ impl Wallets{
     }
 
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&self.path)?;
 
         for(address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;